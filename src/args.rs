@@ -1,33 +1,191 @@
-use clap::{command, ArgGroup, Parser};
-use std::{path::PathBuf, time::Duration};
+use clap::{error::ErrorKind, ArgAction, ArgGroup, CommandFactory, Parser, Subcommand};
+use log::LevelFilter;
+use std::{collections::HashSet, path::PathBuf, time::Duration};
+use tokio_serial::Parity;
+
+use crate::{
+    device::{FreqScale, SerialDeviceBuilder, ZeroVelocity, BAUD_RATE_RANGE},
+    drums::{self, DrumMap},
+    midi::{EventClass, EventKind, OctaveNaming, TimingMode, TimingOverrides},
+    play::{OutOfRange, OCTAVE_8_FREQS},
+    tempo_file::Interpolation,
+    voices::{CullPolicy, CulledNote},
+};
 
 #[derive(Parser)]
+#[command(version, args_conflicts_with_subcommands = true)]
 #[command(group(
     ArgGroup::new("speed_components")
         .required(false)
         .multiple(true)
-        .args(["pitch_shift", "tempo_shift"])
+        .args(["pitch_shift", "tempo_shift", "octave"])
 ))]
 struct RawArgs {
-    file: PathBuf,
-    #[arg(short, long, default_value_t = 250000)]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    file: Option<PathBuf>,
+    #[arg(short, long, default_value_t = 250000, global = true, value_parser = parse_baud_rate)]
     baudrate: u32,
 
+    /// the serial port of the device, instead of choosing from the available ports
+    #[arg(long, global = true)]
+    port: Option<PathBuf>,
+
+    /// choose from the available ports even when the one chosen last time is there
+    #[arg(long, conflicts_with = "port", global = true)]
+    pick_port: bool,
+
     #[arg(short = 't', long)]
     assume_initial_tick: Option<u64>,
 
+    /// the tempo to play a metrically timed file at until its first tempo update, for files
+    /// that do not set one before their first note
+    #[arg(long, conflicts_with = "assume_initial_tick")]
+    default_bpm: Option<f64>,
+
+    /// follow only the tempo updates of the track with this index, for files whose tracks carry
+    /// conflicting tempos. The tempo updates of all tracks are followed otherwise
+    #[arg(long, value_name = "INDEX")]
+    tempo_track: Option<usize>,
+
+    /// override the ticks per beat from the header of a metrically timed file
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    ticks_per_beat: Option<u32>,
+
+    /// read the division of the header as this kind of timing, whatever the header says
+    #[arg(long, value_parser = ["metrical", "timecode"])]
+    timing_mode: Option<String>,
+
+    /// frames per second of --timing-mode timecode
+    #[arg(long, value_parser = ["24", "25", "29", "30"], requires = "timing_mode", required_if_eq("timing_mode", "timecode"))]
+    fps: Option<String>,
+
+    /// ticks per frame of --timing-mode timecode
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..), requires = "timing_mode", required_if_eq("timing_mode", "timecode"))]
+    ticks_per_frame: Option<u8>,
+
     #[arg(long, num_args = 1..)]
     tracks: Option<Vec<usize>>,
 
     #[arg(short, long)]
     dry: bool,
 
+    /// print the bytes every message would be sent to a serial device as hex, with the time
+    /// since the device was opened
+    #[arg(long, requires = "dry")]
+    dump_protocol: bool,
+
+    /// fail instead of warning when a selected track has no notes
+    #[arg(long)]
+    strict: bool,
+
+    /// skip the tracks of a damaged file that fail to parse and play the rest, instead of
+    /// failing
+    #[arg(long)]
+    lenient: bool,
+
     #[arg(short, long)]
     list: bool,
 
-    #[arg(long)]
+    /// log more details, repeat for even more
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// only log warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+
+    #[arg(long, global = true)]
     ignore_id: bool,
 
+    /// don't ask the device for its ID before playing
+    #[arg(long, conflicts_with = "ignore_id")]
+    skip_handshake: bool,
+
+    /// reset the device right after connecting, so nothing it sounds from before is left on
+    #[arg(long, default_value_t = true, action = ArgAction::Set)]
+    clear_on_start: bool,
+
+    /// the byte that ends tone updates, as 2 hex digits, for firmware revisions that delimit
+    /// them with another byte than 01
+    #[arg(long, value_parser = parse_tone_trailer, global = true)]
+    tone_trailer: Option<u8>,
+
+    /// end tone updates without a trailer, for firmware revisions that frame them by length
+    #[arg(long, conflicts_with = "tone_trailer", global = true)]
+    no_tone_trailer: bool,
+
+    /// send the frequencies of the notes in whole hertz, or in hundredths of a hertz for
+    /// firmware that takes them, which keeps the low notes in tune. The device is asked
+    /// whether it does during the ID handshake
+    #[arg(long, value_parser = ["hz", "cHz"], default_value = "hz", global = true)]
+    freq_scale: String,
+
+    /// what stopping a tone is sent as, for firmware that tells them apart: a note off message
+    /// that releases the voice, or a tone update with velocity 0 that keeps it configured but
+    /// muted. Without it tones are stopped with tone updates with velocity 0, which every
+    /// firmware releases the voice on. The bundled firmware releases it on both
+    #[arg(long, value_parser = ["note-off", "sustain-silent"])]
+    zero_vel: Option<String>,
+
+    /// send the release velocity of MIDI note offs along with them, for firmware with release
+    /// dynamics
+    #[arg(long)]
+    release_velocity: bool,
+
+    /// the ID the device has to answer with, as 8 hex digits
+    #[arg(long, value_parser = parse_device_id, global = true)]
+    device_id: Option<[u8; 4]>,
+
+    /// the parity of the serial connection
+    #[arg(long, value_parser = ["none", "odd", "even"], default_value = "none", global = true)]
+    parity: String,
+
+    /// drive a speaker from the hardware PWM channel CHIP:CHANNEL through sysfs instead of a
+    /// serial device
+    #[cfg(feature = "pwm")]
+    #[arg(long, value_parser = parse_pwm_channel, global = true)]
+    pwm: Option<(u32, u32)>,
+
+    /// send the notes to a raw MIDI output device, like /dev/snd/midiC1D0, to play them on a
+    /// synthesizer instead of a serial device
+    #[arg(long, global = true)]
+    midi_out: Option<PathBuf>,
+
+    /// the MIDI channel --midi-out sends the notes on
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..16), requires = "midi_out", global = true)]
+    midi_out_channel: u8,
+
+    /// fail when a write to the device blocks for longer than this many ms
+    #[arg(long, default_value_t = 1000, global = true)]
+    write_timeout: u64,
+
+    /// fail when the device takes longer than this many ms to answer the ID handshake
+    #[arg(
+        long,
+        default_value_t = 1000,
+        conflicts_with = "skip_handshake",
+        global = true
+    )]
+    id_timeout: u64,
+
+    /// play a scale on the device to check that it works and exit
+    #[arg(long, conflicts_with = "file")]
+    test: bool,
+
+    /// list the available serial ports and exit
+    #[arg(long)]
+    list_ports: bool,
+
+    /// print the configuration resolved from the arguments and exit
+    #[arg(long)]
+    show_config: bool,
+
+    /// go on after printing the configuration for --show-config
+    #[arg(long, requires = "show_config")]
+    run: bool,
+
     #[arg(
         long,
         allow_negative_numbers = true,
@@ -40,6 +198,447 @@ struct RawArgs {
 
     #[arg(long, allow_negative_numbers = true)]
     tempo_shift: Option<i8>,
+
+    /// shift the pitch by this many octaves, on top of --pitch-shift
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i8).range(-10..=10))]
+    octave: Option<i8>,
+
+    /// add this to the key of every note as it is read, for files whose keys are numbered off
+    /// from standard MIDI. Keys are kept within 0 to 127
+    #[arg(long, allow_negative_numbers = true)]
+    key_offset: Option<i8>,
+
+    /// start every track at its first note instead of all of them together, leaving out the
+    /// silence before it
+    #[arg(long)]
+    align_first_note: bool,
+
+    /// play the notes of every channel of the track with this index as a track of its own, for
+    /// tracks that interleave several parts
+    #[arg(long, value_name = "INDEX")]
+    split_track_channels: Option<usize>,
+
+    /// re-send sounding notes with the aftertouch pressure as their new velocity, the firmware
+    /// must be able to change the velocity of a sounding tone without re-triggering it
+    #[arg(long)]
+    aftertouch: bool,
+
+    /// ramp the velocity of notes up to their target over the first <SOFT_START> ms of playback
+    #[arg(long)]
+    soft_start: Option<u64>,
+
+    /// fade out what is sounding over <SOFT_RESET> ms before resetting the device when playback
+    /// is stopped or ends, instead of cutting it off. The firmware must be able to change the
+    /// velocity of a sounding tone without re-triggering it
+    #[arg(long)]
+    soft_reset: Option<u64>,
+
+    /// keep these tracks playing without sending their notes
+    #[arg(long, num_args = 1..)]
+    mute: Vec<usize>,
+
+    /// only send the notes of these tracks, the others keep playing silently
+    #[arg(long, num_args = 1..)]
+    solo: Vec<usize>,
+
+    /// scale the velocity of a track's notes, given as INDEX:SCALE, 1 for tracks not given
+    #[arg(long, num_args = 1.., value_parser = parse_track_volume)]
+    track_volume: Vec<(usize, f64)>,
+
+    /// play a track this many ms later than the others, or earlier when negative, given as
+    /// INDEX:MS. The earliest track starts with playback, the others that much later
+    #[arg(long, num_args = 1.., value_parser = parse_track_offset)]
+    track_offset: Vec<(usize, i64)>,
+
+    /// sound the notes of a MIDI channel on a voice of the device, given as CHANNEL:VOICE. The
+    /// device picks the voice for the notes of channels not given
+    #[arg(long, num_args = 1.., value_parser = parse_channel_voice)]
+    channel_voice: Vec<(u8, u8)>,
+
+    /// 12 comma separated frequencies in Hz for the notes C8 to B8, or their distance from C8 in
+    /// cents when suffixed with `c`, replacing the equal temperament table
+    #[arg(long, value_delimiter = ',', value_parser = parse_note_table_value)]
+    note_table: Option<Vec<f64>>,
+
+    /// the frequencies in Hz the speakers can play, given as LOW:HIGH
+    #[arg(long, value_parser = parse_freq_range)]
+    freq_range: Option<(u16, u16)>,
+
+    /// what to do with notes outside of --freq-range or transposed past the MIDI keys: move them
+    /// to the edge, move them by octaves until they fit, play them at `fixed:<hz>`, or drop them
+    #[arg(long, value_parser = parse_out_of_range, default_value = "drop")]
+    out_of_range: OutOfRange,
+
+    /// log the frequency every note is sent at and how many cents it is off from equal
+    /// temperament, to check the tuning
+    #[arg(long)]
+    show_cents: bool,
+
+    /// whether key 60 is named C4 or C5 in printed notes, like in --show-cents, --dump-events
+    /// and --piano-roll. The frequencies stay the same
+    #[arg(long, value_parser = ["c4", "c5"], default_value = "c4", global = true)]
+    octave_naming: String,
+
+    /// log every note that starts while its track already has a sounding note
+    #[arg(long)]
+    warn_polyphony: bool,
+
+    /// abort when a note starts while its track already has a sounding note
+    #[arg(long)]
+    error_polyphony: bool,
+
+    /// print the notes of the selected tracks as a text piano roll and exit
+    #[arg(long, requires = "dry")]
+    piano_roll: bool,
+
+    /// jump back from B to A, given in seconds as A:B, once reached during playback
+    #[arg(long, value_parser = parse_repeat_section)]
+    repeat: Option<(Duration, Duration)>,
+
+    /// how many times to jump back, forever if not given
+    #[arg(long, requires = "repeat")]
+    repeat_count: Option<u32>,
+
+    /// transpose by this many semitones more on every jump back, repeating only as long as all
+    /// keys stay within 0 to 127
+    #[arg(long, requires = "repeat", allow_negative_numbers = true)]
+    transpose_each_loop: Option<i8>,
+
+    /// print the raw and converted events of this track and exit
+    #[arg(long, conflicts_with_all = ["list", "piano_roll", "info"])]
+    dump_events: Option<usize>,
+
+    /// write the events of the selected tracks to this file as JSON, with their time in ms and
+    /// the frequencies the notes are played at, and exit
+    #[arg(long, requires = "file", conflicts_with_all = ["list", "piano_roll", "info", "print_events", "dump_events"])]
+    export_json: Option<PathBuf>,
+
+    /// list how many events of every type the file contains that are ignored during playback
+    #[arg(long)]
+    report_unsupported: bool,
+
+    /// print the header and track details of the file and exit
+    #[arg(long, conflicts_with_all = ["list", "piano_roll"])]
+    info: bool,
+
+    /// print the path, format, track count, note count and duration in seconds of the file on
+    /// a single line separated by tabs and exit, logging only warnings and errors
+    #[arg(long, requires = "file", conflicts_with_all = ["list", "piano_roll", "info", "dump_events", "export_json"])]
+    count_only: bool,
+
+    /// leave out notes so that no more than this many sound at once across all tracks
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_voices: Option<u32>,
+
+    /// which of the notes starting together to leave out first when over --max-voices
+    #[arg(long, value_parser = ["lowest-velocity", "lowest-pitch", "highest-pitch"], default_value = "lowest-velocity")]
+    cull_policy: String,
+
+    /// wait for the SMPTE offset of the file before starting, so that playback is aligned with
+    /// a timeline started along with it
+    #[arg(long)]
+    honor_smpte_offset: bool,
+
+    /// measure the latency of the device by pressing a key whenever a tone is heard, and
+    /// remember it for --latency-offset
+    #[arg(long, conflicts_with_all = ["file", "test"])]
+    measure_latency: bool,
+
+    /// play every note this many ms early to make up for the latency of the device, the one
+    /// remembered by --measure-latency if no value is given
+    #[arg(long, num_args = 0..=1)]
+    latency_offset: Option<Option<u64>>,
+
+    /// sound a constant tone of this frequency in Hz alongside the file, to compare the pitch of
+    /// the device against. Takes a voice of the device for the whole playback
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    reference_tone: Option<u16>,
+
+    /// release notes that have been sounding for this many seconds, for files that lose note
+    /// offs along the way
+    #[arg(long)]
+    max_note_duration: Option<f64>,
+
+    /// reset the device when notes have been left sounding with no note starting or stopping
+    /// for this many seconds, which most likely means note offs were lost
+    #[arg(long, conflicts_with = "hold_end")]
+    stuck_note_timeout: Option<f64>,
+
+    /// ramp linearly to the tempo of a tempo update over <SMOOTH_TEMPO> ms, instead of
+    /// switching at once
+    #[arg(long, conflicts_with = "fixed_tempo")]
+    smooth_tempo: Option<u64>,
+
+    /// ignore the tempo updates of the file and keep playing at the initial tempo
+    #[arg(long)]
+    fixed_tempo: bool,
+
+    /// the tempo to keep playing at instead of the initial one
+    #[arg(long, requires = "fixed_tempo", conflicts_with = "assume_initial_tick")]
+    bpm: Option<f64>,
+
+    /// follow the `time_seconds bpm` lines of this file instead of the tempo updates of the
+    /// MIDI file
+    #[arg(long, conflicts_with = "fixed_tempo")]
+    tempo_map: Option<PathBuf>,
+
+    /// how the tempo changes between the points of the tempo map
+    #[arg(long, value_parser = ["stepped", "linear"], default_value = "stepped")]
+    tempo_map_interpolation: String,
+
+    /// change the duty cycle of tones with their velocity, from 50% at full velocity down
+    /// following (velocity / 127)^<DUTY_MAP>, 1 being linear
+    #[arg(long)]
+    duty_map: Option<f64>,
+
+    /// keep the notes that are sounding at the end of the file sounding until Ctrl-C, instead of
+    /// releasing them
+    #[arg(long, conflicts_with_all = ["arpeggiate", "glide"])]
+    hold_end: bool,
+
+    /// play notes with a velocity of 128 - velocity, to check what the velocity changes
+    #[arg(long)]
+    invert_velocity: bool,
+
+    /// only act on these kinds of events during playback and ignore the others, to find out
+    /// which of them causes a problem
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = ["note", "aftertouch", "controller", "tempo", "directive", "tuning"],
+    )]
+    enable_events: Option<Vec<String>>,
+
+    /// only act on notes during playback, the same as --enable-events note
+    #[arg(long, conflicts_with = "enable_events")]
+    only_notes: bool,
+
+    /// play the notes on channel 10 as General MIDI percussion, at a fixed frequency per drum
+    #[arg(long)]
+    drum_mode: bool,
+
+    /// `key frequency` lines replacing entries of the percussion map, 0 removes a key
+    #[arg(long, requires = "drum_mode")]
+    drum_map: Option<PathBuf>,
+
+    /// play the `time_ms frequency_hz velocity` lines of this file instead of a MIDI file
+    #[arg(long, conflicts_with_all = ["file", "test", "list", "info", "piano_roll", "midi_in"])]
+    freq_script: Option<PathBuf>,
+
+    /// play the notes arriving on a raw MIDI input device, like /dev/snd/midiC1D0, as they
+    /// arrive instead of a file
+    #[arg(long, conflicts_with_all = ["file", "test", "list", "info", "piano_roll"])]
+    midi_in: Option<PathBuf>,
+
+    /// play these files along with FILE from the same start. Their tracks are numbered on from
+    /// the last track of the file before, for --mute, --solo and --track-volume
+    #[arg(long, num_args = 1.., requires = "file", conflicts_with_all = ["tempo_map", "repeat", "info", "piano_roll", "print_events", "dump_events"])]
+    layer: Vec<PathBuf>,
+
+    /// jump to the positions read from stdin as they arrive, one per line in seconds or in ticks
+    /// when suffixed with `t`, waiting for the next one at the end instead of stopping. A serial
+    /// device needs --port, as stdin can't also be read for choosing one
+    #[arg(long, requires = "file", conflicts_with_all = ["tempo_map", "repeat", "layer", "info", "piano_roll", "print_events", "dump_events", "pick_port"])]
+    scrub: bool,
+
+    /// also send every note as an OSC message `/note track key velocity frequency` to this
+    /// HOST:PORT over UDP, use --dry to only send them there
+    #[arg(long)]
+    osc: Option<String>,
+
+    /// cycle through the notes a track holds at once at <ARPEGGIATE> notes per second, so that
+    /// chords can be heard on a single speaker
+    #[arg(long)]
+    arpeggiate: Option<f64>,
+
+    /// slide to the pitch of every note from the one before over <GLIDE> ms when they overlap
+    /// or follow right after each other, playing the notes of each track as a single voice,
+    /// except those on channel 10
+    #[arg(long, value_name = "MS", conflicts_with = "arpeggiate")]
+    glide: Option<u64>,
+
+    /// how many steps per second a slide of --glide is sent in, each is a note off and a tone
+    /// update. Rates above 1000 are sent at 1000
+    #[arg(long, default_value_t = 100.0, requires = "glide")]
+    glide_rate: f64,
+
+    /// print the events of the selected tracks as they happen instead of playing them
+    #[arg(long, conflicts_with_all = ["list", "info", "piano_roll"])]
+    print_events: bool,
+
+    /// time per row of the piano roll in ms
+    #[arg(long, default_value_t = 100)]
+    piano_roll_resolution: u64,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// silence the device and exit
+    Reset {
+        /// don't ask the device for its ID before resetting it
+        #[arg(long)]
+        no_id: bool,
+    },
+}
+
+#[cfg(feature = "pwm")]
+fn parse_pwm_channel(s: &str) -> Result<(u32, u32), String> {
+    let (chip, channel) = s.split_once(':').ok_or("expected CHIP:CHANNEL")?;
+
+    Ok((
+        chip.parse().map_err(|e| format!("invalid chip: {e}"))?,
+        channel
+            .parse()
+            .map_err(|e| format!("invalid channel: {e}"))?,
+    ))
+}
+
+fn parse_baud_rate(s: &str) -> Result<u32, String> {
+    // parsed wide, so that absurdly large values get the same error as other out of range ones
+    let baud_rate = s.parse::<u64>().map_err(|e| e.to_string())?;
+
+    u32::try_from(baud_rate)
+        .ok()
+        .filter(|baud_rate| BAUD_RATE_RANGE.contains(baud_rate))
+        .ok_or_else(|| {
+            format!(
+                "baud rate must be between {} and {}",
+                BAUD_RATE_RANGE.start(),
+                BAUD_RATE_RANGE.end()
+            )
+        })
+}
+
+fn parse_device_id(s: &str) -> Result<[u8; 4], String> {
+    u32::from_str_radix(s, 16)
+        .ok()
+        .filter(|_| s.len() == 8)
+        .map(u32::to_be_bytes)
+        .ok_or_else(|| "expected 8 hex digits".into())
+}
+
+fn parse_tone_trailer(s: &str) -> Result<u8, String> {
+    u8::from_str_radix(s, 16)
+        .ok()
+        .filter(|_| s.len() == 2)
+        .ok_or_else(|| "expected 2 hex digits".into())
+}
+
+fn parse_repeat_section(s: &str) -> Result<(Duration, Duration), String> {
+    let (start, end) = s.split_once(':').ok_or("expected A:B")?;
+
+    let parse_seconds = |s: &str| {
+        s.trim()
+            .parse::<f64>()
+            .map_err(|e| e.to_string())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string()))
+    };
+
+    let (start, end) = (parse_seconds(start)?, parse_seconds(end)?);
+
+    if start < end {
+        Ok((start, end))
+    } else {
+        Err("the start of the section must be before its end".into())
+    }
+}
+
+fn parse_track_volume(s: &str) -> Result<(usize, f64), String> {
+    let (index, scale) = s.split_once(':').ok_or("expected INDEX:SCALE")?;
+
+    let index = index
+        .parse()
+        .map_err(|e| format!("invalid track index: {e}"))?;
+    let scale = scale
+        .parse::<f64>()
+        .ok()
+        .filter(|scale| scale.is_finite() && *scale >= 0.0)
+        .ok_or("the scale must be a non-negative number")?;
+
+    Ok((index, scale))
+}
+
+// up to an hour either way
+const TRACK_OFFSET_RANGE: std::ops::RangeInclusive<i64> = -3_600_000..=3_600_000;
+
+fn parse_track_offset(s: &str) -> Result<(usize, i64), String> {
+    let (index, offset) = s.split_once(':').ok_or("expected INDEX:MS")?;
+
+    let index = index
+        .parse()
+        .map_err(|e| format!("invalid track index: {e}"))?;
+    let offset = offset.parse().map_err(|e| format!("invalid offset: {e}"))?;
+    if !TRACK_OFFSET_RANGE.contains(&offset) {
+        return Err(format!(
+            "the offset must be between {} and {} ms",
+            TRACK_OFFSET_RANGE.start(),
+            TRACK_OFFSET_RANGE.end()
+        ));
+    }
+
+    Ok((index, offset))
+}
+
+fn parse_channel_voice(s: &str) -> Result<(u8, u8), String> {
+    let (channel, voice) = s.split_once(':').ok_or("expected CHANNEL:VOICE")?;
+
+    let channel = channel
+        .parse::<u8>()
+        .ok()
+        .filter(|channel| *channel < 16)
+        .ok_or("the channel must be between 0 and 15")?;
+    let voice = voice.parse().map_err(|e| format!("invalid voice: {e}"))?;
+
+    Ok((channel, voice))
+}
+
+fn parse_freq_range(s: &str) -> Result<(u16, u16), String> {
+    let (low, high) = s.split_once(':').ok_or("expected LOW:HIGH")?;
+
+    let low = low.parse().map_err(|e| format!("invalid low: {e}"))?;
+    let high = high.parse().map_err(|e| format!("invalid high: {e}"))?;
+    if low > high {
+        return Err("the low frequency must not be above the high one".into());
+    }
+
+    Ok((low, high))
+}
+
+fn parse_out_of_range(s: &str) -> Result<OutOfRange, String> {
+    match s {
+        "clamp" => Ok(OutOfRange::Clamp),
+        "fold" => Ok(OutOfRange::Fold),
+        "drop" => Ok(OutOfRange::Drop),
+        _ => {
+            let frequency = s
+                .strip_prefix("fixed:")
+                .ok_or("expected clamp, fold, fixed:<hz> or drop")?;
+
+            frequency
+                .parse::<u16>()
+                .ok()
+                .filter(|frequency| *frequency > 0)
+                .map(OutOfRange::Fixed)
+                .ok_or_else(|| format!("invalid frequency: {frequency}"))
+        }
+    }
+}
+
+fn parse_note_table_value(s: &str) -> Result<f64, String> {
+    let frequency = match s.strip_suffix('c') {
+        Some(cents) => cents
+            .trim()
+            .parse::<f64>()
+            .map(|cents| OCTAVE_8_FREQS[0] * 2.0f64.powf(cents / 1200.0)),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|e| e.to_string())?;
+
+    if frequency.is_finite() && frequency > 0.0 {
+        Ok(frequency)
+    } else {
+        Err("frequency must be positive".into())
+    }
 }
 
 fn delta_note_to_multiplier(delta: i8) -> f64 {
@@ -52,22 +651,169 @@ pub struct Speed {
     pub pitch: f64,
 }
 
+#[derive(Debug, Clone)]
+pub struct PlayOptions {
+    pub speed: Speed,
+    pub aftertouch: bool,
+    pub soft_start: Option<Duration>,
+    // how long to fade out before a reset, for --soft-reset
+    pub soft_reset: Option<Duration>,
+    pub smooth_tempo: Option<Duration>,
+    pub max_note_duration: Option<Duration>,
+    // how much earlier than their time notes are played
+    pub latency_offset: Duration,
+    // how long to wait before starting, for --honor-smpte-offset
+    pub smpte_offset: Duration,
+    pub mute: Vec<usize>,
+    pub solo: Vec<usize>,
+    pub track_volume: Vec<(usize, f64)>,
+    pub track_offset: Vec<(usize, i64)>,
+    pub channel_voice: Vec<(u8, u8)>,
+    pub note_table: [f64; 12],
+    pub freq_range: Option<(u16, u16)>,
+    pub out_of_range: OutOfRange,
+    pub show_cents: bool,
+    pub octave_naming: OctaveNaming,
+    pub warn_polyphony: bool,
+    pub error_polyphony: bool,
+    pub fixed_tempo: bool,
+    // the tempo comes from a tempo file instead of the tracks
+    pub external_tempo: bool,
+    pub arpeggiate: Option<Duration>,
+    // how long a slide takes and how long each step of it lasts
+    pub glide: Option<(Duration, Duration)>,
+    pub duty_map: Option<f64>,
+    pub hold_end: bool,
+    pub invert_velocity: bool,
+    pub release_velocity: bool,
+    // the kinds of events acted on, all of them if None
+    pub enabled_events: Option<HashSet<EventClass>>,
+    // set for --drum-mode
+    pub drum_map: Option<DrumMap>,
+    // notes left out for --max-voices
+    pub culled_notes: HashSet<CulledNote>,
+}
+
+impl PlayOptions {
+    pub fn is_audible(&self, track_index: usize) -> bool {
+        (self.solo.is_empty() || self.solo.contains(&track_index))
+            && !self.mute.contains(&track_index)
+    }
+
+    /// Whether playback acts on the event, for --enable-events. Events that aren't acted on
+    /// anyway are always enabled
+    pub fn is_enabled(&self, event: &EventKind) -> bool {
+        match (&self.enabled_events, event.class()) {
+            (Some(enabled), Some(class)) => enabled.contains(&class),
+            _ => true,
+        }
+    }
+
+    /// The scale of --track-volume for a track, the last one given wins
+    pub fn track_volume(&self, track_index: usize) -> f64 {
+        self.track_volume
+            .iter()
+            .rev()
+            .find(|(index, _)| *index == track_index)
+            .map_or(1.0, |(_, scale)| *scale)
+    }
+
+    /// The offset of --track-offset for a track in ms, the last one given wins
+    pub fn track_offset(&self, track_index: usize) -> i64 {
+        self.track_offset
+            .iter()
+            .rev()
+            .find(|(index, _)| *index == track_index)
+            .map_or(0, |(_, offset)| *offset)
+    }
+
+    /// How much later than the sequence a track is played for --track-offset, counted from the
+    /// earliest track
+    pub fn track_delay(&self, track_index: usize) -> Duration {
+        let earliest = self
+            .track_offset
+            .iter()
+            .map(|(index, _)| self.track_offset(*index))
+            .min()
+            .unwrap_or(0)
+            .min(0);
+        Duration::from_millis((self.track_offset(track_index) - earliest) as u64)
+    }
+
+    /// The voice of --channel-voice for a channel, the last one given wins
+    pub fn channel_voice(&self, channel: u8) -> Option<u8> {
+        self.channel_voice
+            .iter()
+            .rev()
+            .find(|(mapped, _)| *mapped == channel)
+            .map(|(_, voice)| *voice)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Args {
-    pub file_path: PathBuf,
-    pub baud_rate: u32,
+    pub file_path: Option<PathBuf>,
+    pub serial: SerialDeviceBuilder,
+    #[cfg(feature = "pwm")]
+    pub pwm: Option<(u32, u32)>,
+    pub reset: bool,
     pub tracks: Option<Vec<usize>>,
     pub dry_run: bool,
-    pub speed: Speed,
-    pub initial_tick: Option<Duration>,
+    pub dump_protocol: bool,
+    pub zero_velocity: ZeroVelocity,
+    pub strict: bool,
+    pub lenient: bool,
+    pub play_options: PlayOptions,
+    pub timing: TimingOverrides,
     pub list: bool,
-    pub ignore_id: bool,
+    pub log_level: LevelFilter,
+    pub list_ports: bool,
+    // None when not showing the configuration, otherwise whether to go on after showing it
+    pub show_config: Option<bool>,
+    pub test: bool,
+    pub piano_roll: Option<Duration>,
+    pub print_events: bool,
+    pub info: bool,
+    pub dump_events: Option<usize>,
+    pub count_only: bool,
+    pub export_json: Option<PathBuf>,
+    pub report_unsupported: bool,
+    pub reference_tone: Option<u16>,
+    pub stuck_note_timeout: Option<Duration>,
+    pub measure_latency: bool,
+    pub honor_smpte_offset: bool,
+    // None if --latency-offset is given without a value
+    pub latency_offset: Option<Option<Duration>>,
+    pub max_voices: Option<(usize, CullPolicy)>,
+    pub bpm: Option<f64>,
+    pub tempo_file: Option<(PathBuf, Interpolation)>,
+    pub drum_map_file: Option<PathBuf>,
+    pub midi_in: Option<PathBuf>,
+    pub midi_out: Option<(PathBuf, u8)>,
+    pub osc: Option<String>,
+    pub layers: Vec<PathBuf>,
+    pub scrub: bool,
+    pub freq_script: Option<PathBuf>,
+    pub repeat: Option<(Duration, Duration)>,
+    pub repeat_count: Option<u32>,
+    pub transpose_each_loop: i8,
+    pub key_offset: i8,
+    pub align_first_note: bool,
+    pub split_track_channels: Option<usize>,
 }
 
 impl Args {
     pub fn parse() -> Args {
-        let args = RawArgs::parse();
+        Self::from_raw(RawArgs::parse())
+    }
 
+    /// Parses the given command line instead of the one the process was started with
+    #[cfg(test)]
+    pub fn parse_from(command_line: &[&str]) -> Args {
+        Self::from_raw(RawArgs::parse_from(command_line))
+    }
+
+    fn from_raw(args: RawArgs) -> Args {
         let (pitch_multiplier, tempo_multiplier) = if let Some(speed_shift) = args.speed_shift {
             (
                 delta_note_to_multiplier(speed_shift),
@@ -75,11 +821,175 @@ impl Args {
             )
         } else {
             (
-                delta_note_to_multiplier(args.pitch_shift.unwrap_or(0)),
+                delta_note_to_multiplier(
+                    args.pitch_shift
+                        .unwrap_or(0)
+                        .saturating_add(args.octave.unwrap_or(0) * 12),
+                ),
                 delta_note_to_multiplier(args.tempo_shift.unwrap_or(0)),
             )
         };
 
+        let note_table = match args.note_table {
+            Some(values) => values.try_into().unwrap_or_else(|values: Vec<f64>| {
+                RawArgs::command()
+                    .error(
+                        ErrorKind::WrongNumberOfValues,
+                        format!("--note-table takes 12 values, got {}", values.len()),
+                    )
+                    .exit()
+            }),
+            None => OCTAVE_8_FREQS,
+        };
+
+        if args.timing_mode.as_deref() == Some("metrical")
+            && (args.fps.is_some() || args.ticks_per_frame.is_some())
+        {
+            RawArgs::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--fps and --ticks-per-frame only apply to --timing-mode timecode",
+                )
+                .exit()
+        }
+
+        if args.bpm.is_some_and(|bpm| !(bpm.is_finite() && bpm > 0.0)) {
+            RawArgs::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    "--bpm must be a positive number",
+                )
+                .exit()
+        }
+
+        if args
+            .default_bpm
+            .is_some_and(|bpm| !(bpm.is_finite() && bpm > 0.0))
+        {
+            RawArgs::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    "--default-bpm must be a positive number",
+                )
+                .exit()
+        }
+
+        let arpeggiate = args.arpeggiate.map(|rate| {
+            Duration::try_from_secs_f64(1.0 / rate)
+                .ok()
+                .filter(|_| rate > 0.0)
+                .unwrap_or_else(|| {
+                    RawArgs::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            "--arpeggiate must be a positive number",
+                        )
+                        .exit()
+                })
+        });
+
+        // two messages per step, which already takes a fair share of the serial link at the
+        // highest rate
+        const MAX_GLIDE_RATE: f64 = 1000.0;
+        let glide_step = Some(args.glide_rate)
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+            .and_then(|rate| Duration::try_from_secs_f64(1.0 / rate.min(MAX_GLIDE_RATE)).ok())
+            .unwrap_or_else(|| {
+                RawArgs::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        "--glide-rate must be a positive number",
+                    )
+                    .exit()
+            });
+
+        // choosing the port reads the choice from stdin, which --scrub reads the positions from
+        #[cfg(all(feature = "pwm", target_os = "linux"))]
+        let serial_device = args.pwm.is_none() && args.midi_out.is_none();
+        #[cfg(not(all(feature = "pwm", target_os = "linux")))]
+        let serial_device = args.midi_out.is_none();
+        if args.scrub && serial_device && !args.dry && args.port.is_none() {
+            RawArgs::command()
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "--scrub reads the positions from stdin, give the serial port with --port \
+                     instead of choosing it there",
+                )
+                .exit()
+        }
+
+        let mut serial = SerialDeviceBuilder::new()
+            .baud(args.baudrate)
+            .ignore_id_mismatch(args.ignore_id)
+            .skip_handshake(args.skip_handshake)
+            .clear_on_start(args.clear_on_start)
+            .note_off_message(args.zero_vel.is_some())
+            .write_timeout(Duration::from_millis(args.write_timeout))
+            .id_timeout(Duration::from_millis(args.id_timeout))
+            .freq_scale(match args.freq_scale.as_str() {
+                "cHz" => FreqScale::Centihertz,
+                _ => FreqScale::Hertz,
+            })
+            .parity(match args.parity.as_str() {
+                "odd" => Parity::Odd,
+                "even" => Parity::Even,
+                _ => Parity::None,
+            });
+        if let Some(Command::Reset { no_id: true }) = args.command {
+            serial = serial.skip_handshake(true);
+        }
+        serial = serial.pick_port(args.pick_port);
+        if let Some(port) = args.port {
+            serial = serial.port(port);
+        }
+        if let Some(device_id) = args.device_id {
+            serial = serial.device_id(device_id);
+        }
+        if args.no_tone_trailer {
+            serial = serial.tone_trailer(None);
+        } else if let Some(tone_trailer) = args.tone_trailer {
+            serial = serial.tone_trailer(Some(tone_trailer));
+        }
+
+        let duty_map = args.duty_map.inspect(|exponent| {
+            if !(exponent.is_finite() && *exponent > 0.0) {
+                RawArgs::command()
+                    .error(
+                        ErrorKind::ValueValidation,
+                        "--duty-map must be a positive number",
+                    )
+                    .exit()
+            }
+        });
+
+        let stuck_note_timeout = args.stuck_note_timeout.map(|seconds| {
+            Duration::try_from_secs_f64(seconds)
+                .ok()
+                .filter(|duration| !duration.is_zero())
+                .unwrap_or_else(|| {
+                    RawArgs::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            "--stuck-note-timeout must be a positive number",
+                        )
+                        .exit()
+                })
+        });
+
+        let max_note_duration = args.max_note_duration.map(|seconds| {
+            Duration::try_from_secs_f64(seconds)
+                .ok()
+                .filter(|duration| !duration.is_zero())
+                .unwrap_or_else(|| {
+                    RawArgs::command()
+                        .error(
+                            ErrorKind::ValueValidation,
+                            "--max-note-duration must be a positive number",
+                        )
+                        .exit()
+                })
+        });
+
         let speed = Speed {
             pitch: pitch_multiplier,
             tempo: tempo_multiplier,
@@ -87,13 +997,144 @@ impl Args {
 
         Args {
             file_path: args.file,
-            baud_rate: args.baudrate,
+            serial,
+            #[cfg(feature = "pwm")]
+            pwm: args.pwm,
+            reset: matches!(args.command, Some(Command::Reset { .. })),
             tracks: args.tracks,
             dry_run: args.dry,
-            speed,
-            initial_tick: args.assume_initial_tick.map(Duration::from_micros),
+            dump_protocol: args.dump_protocol,
+            zero_velocity: match args.zero_vel.as_deref() {
+                Some("sustain-silent") => ZeroVelocity::SustainSilent,
+                _ => ZeroVelocity::NoteOff,
+            },
+            strict: args.strict,
+            lenient: args.lenient,
+            play_options: PlayOptions {
+                speed,
+                aftertouch: args.aftertouch,
+                soft_start: args.soft_start.map(Duration::from_millis),
+                soft_reset: args.soft_reset.map(Duration::from_millis),
+                smooth_tempo: args.smooth_tempo.map(Duration::from_millis),
+                max_note_duration,
+                latency_offset: Duration::ZERO,
+                smpte_offset: Duration::ZERO,
+                mute: args.mute,
+                solo: args.solo,
+                track_volume: args.track_volume,
+                track_offset: args.track_offset,
+                channel_voice: args.channel_voice,
+                note_table,
+                freq_range: args.freq_range,
+                out_of_range: args.out_of_range,
+                show_cents: args.show_cents,
+                octave_naming: match args.octave_naming.as_str() {
+                    "c5" => OctaveNaming::C5,
+                    _ => OctaveNaming::C4,
+                },
+                warn_polyphony: args.warn_polyphony,
+                error_polyphony: args.error_polyphony,
+                fixed_tempo: args.fixed_tempo,
+                external_tempo: args.tempo_map.is_some(),
+                arpeggiate,
+                glide: args.glide.map(|ms| (Duration::from_millis(ms), glide_step)),
+                duty_map,
+                hold_end: args.hold_end,
+                invert_velocity: args.invert_velocity,
+                release_velocity: args.release_velocity,
+                enabled_events: match (args.enable_events, args.only_notes) {
+                    (Some(events), _) => Some(
+                        events
+                            .iter()
+                            .map(|event| match event.as_str() {
+                                "aftertouch" => EventClass::Aftertouch,
+                                "controller" => EventClass::Controller,
+                                "tempo" => EventClass::Tempo,
+                                "directive" => EventClass::Directive,
+                                "tuning" => EventClass::Tuning,
+                                _ => EventClass::Note,
+                            })
+                            .collect(),
+                    ),
+                    (None, true) => Some(HashSet::from([EventClass::Note])),
+                    (None, false) => None,
+                },
+                drum_map: args.drum_mode.then(drums::gm_drum_map),
+                culled_notes: HashSet::new(),
+            },
+            timing: TimingOverrides {
+                initial_tick: args.assume_initial_tick.map(Duration::from_micros),
+                ticks_per_beat: args.ticks_per_beat,
+                mode: match (args.timing_mode.as_deref(), args.fps, args.ticks_per_frame) {
+                    (Some("metrical"), _, _) => Some(TimingMode::Metrical),
+                    (Some(_), Some(fps), Some(ticks_per_frame)) => Some(TimingMode::Timecode {
+                        fps: fps
+                            .parse()
+                            .ok()
+                            .and_then(midly::Fps::from_int)
+                            .unwrap_or(midly::Fps::Fps30),
+                        ticks_per_frame,
+                    }),
+                    _ => None,
+                },
+                default_bpm: args.default_bpm,
+                tempo_track: args.tempo_track,
+            },
             list: args.list,
-            ignore_id: args.ignore_id,
+            log_level: match (args.quiet || args.count_only, args.verbose) {
+                (true, _) => LevelFilter::Warn,
+                (false, 0) => LevelFilter::Info,
+                (false, 1) => LevelFilter::Debug,
+                (false, _) => LevelFilter::Trace,
+            },
+            list_ports: args.list_ports,
+            show_config: args.show_config.then_some(args.run),
+            test: args.test,
+            piano_roll: args
+                .piano_roll
+                .then(|| Duration::from_millis(args.piano_roll_resolution.max(1))),
+            print_events: args.print_events,
+            info: args.info,
+            dump_events: args.dump_events,
+            count_only: args.count_only,
+            export_json: args.export_json,
+            report_unsupported: args.report_unsupported,
+            reference_tone: args.reference_tone,
+            stuck_note_timeout,
+            measure_latency: args.measure_latency,
+            honor_smpte_offset: args.honor_smpte_offset,
+            latency_offset: args
+                .latency_offset
+                .map(|offset| offset.map(Duration::from_millis)),
+            max_voices: args.max_voices.map(|max_voices| {
+                let policy = match args.cull_policy.as_str() {
+                    "lowest-pitch" => CullPolicy::LowestPitch,
+                    "highest-pitch" => CullPolicy::HighestPitch,
+                    _ => CullPolicy::LowestVelocity,
+                };
+                (max_voices as usize, policy)
+            }),
+            bpm: args.bpm,
+            drum_map_file: args.drum_map,
+            midi_in: args.midi_in,
+            midi_out: args.midi_out.map(|path| (path, args.midi_out_channel)),
+            osc: args.osc,
+            layers: args.layer,
+            scrub: args.scrub,
+            freq_script: args.freq_script,
+            tempo_file: args.tempo_map.map(|path| {
+                let interpolation = match args.tempo_map_interpolation.as_str() {
+                    "linear" => Interpolation::Linear,
+                    _ => Interpolation::Stepped,
+                };
+                (path, interpolation)
+            }),
+            repeat: args.repeat,
+            repeat_count: args.repeat_count,
+            transpose_each_loop: args.transpose_each_loop.unwrap_or(0),
+            key_offset: args.key_offset.unwrap_or(0),
+            align_first_note: args.align_first_note,
+            split_track_channels: args.split_track_channels,
         }
     }
 }
@@ -1,10 +1,23 @@
-use std::{io::Write, path::PathBuf, process::exit, str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    time::Duration,
+};
 
 use async_trait::async_trait;
-use tokio::sync::Mutex;
-use tokio_serial::SerialStream;
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_serial::{SerialPortInfo, SerialPortType, SerialStream, UsbPortInfo};
 
-use crate::DeviceMutex;
+use crate::state;
 
 fn read_input<T, ParseError, Parser: Fn(&str) -> Result<T, ParseError>, Filter: Fn(&T) -> bool>(
     prompt: &str,
@@ -29,19 +42,331 @@ fn read_input<T, ParseError, Parser: Fn(&str) -> Result<T, ParseError>, Filter:
     }
 }
 
+pub fn list_ports() -> Result<Vec<SerialPortInfo>, Box<dyn std::error::Error + Send + Sync>> {
+    let ports = tokio_serial::available_ports()?;
+
+    info!("listing available serial ports...");
+
+    ports
+        .iter()
+        .enumerate()
+        .for_each(|(i, p)| println!("{}: {}", i, p.port_name.split('/').next_back().unwrap()));
+
+    Ok(ports)
+}
+
+pub type BoxedDevice = Box<dyn Device + Send>;
+
 pub async fn new(
-    baud_rate: u32,
     dummy_device: bool,
-    ignore_id: bool,
-) -> Result<Arc<DeviceMutex>, Box<dyn std::error::Error + Send + Sync>> {
-    if dummy_device {
-        println!("using dummy device");
-        Ok(Arc::new(Mutex::new(DummyDevice)))
-    } else {
-        Ok(Arc::new(Mutex::new(
-            SerialDevice::new(baud_rate, ignore_id).await?,
+    dump_protocol: bool,
+    serial: SerialDeviceBuilder,
+) -> Result<BoxedDevice, Box<dyn std::error::Error + Send + Sync>> {
+    if dump_protocol {
+        info!("using dummy device, printing the messages it is sent");
+        Ok(Box::new(DummyDevice::dumping_protocol(
+            serial.tone_trailer,
+            serial.freq_scale,
+            serial.note_off_message,
         )))
+    } else if dummy_device {
+        info!("using dummy device");
+        Ok(Box::new(DummyDevice::default()))
+    } else {
+        Ok(Box::new(SerialDevice::new(serial).await?))
+    }
+}
+
+/// A tone to sound on the device, a velocity of 0 turns it off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tone {
+    pub frequency: u16,
+    pub vel: u8,
+    // the percentage of the period the output is high, left to the device if None
+    pub duty: Option<u8>,
+    // the voice of the device to sound the tone on, left to the device if None
+    pub voice: Option<u8>,
+    // how fast a tone that is turned off was released, sent along for --release-velocity
+    pub release_vel: Option<u8>,
+    // the frequency in hundredths of a hertz, for devices that take it. None for tones that are
+    // given in whole hertz
+    pub centihertz: Option<u32>,
+}
+
+impl Tone {
+    pub fn off(frequency: u16) -> Self {
+        Self {
+            frequency,
+            vel: 0,
+            duty: None,
+            voice: None,
+            release_vel: None,
+            centihertz: None,
+        }
+    }
+
+    /// The tone turned off, on the voice it sounds on
+    pub fn stopped(self) -> Self {
+        Self {
+            voice: self.voice,
+            ..Self::off(self.frequency)
+        }
+    }
+}
+
+/// The whole hertz of a frequency in hundredths of a hertz, which the tones are told apart by
+pub fn centihertz_to_hertz(centihertz: u32) -> u16 {
+    (centihertz / 100).min(u16::MAX as u32) as u16
+}
+
+/// How the frequencies are sent to a serial device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FreqScale {
+    /// whole hertz, which every firmware takes
+    #[default]
+    Hertz,
+    /// hundredths of a hertz in a wider message, for firmware that can sound them
+    Centihertz,
+}
+
+/// What a tone that stops is sent as, for firmware that tells the two apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZeroVelocity {
+    /// a note off, which releases the voice
+    NoteOff,
+    /// a tone update with velocity 0, which keeps the voice configured but muted
+    SustainSilent,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    ToneUpdate(Tone),
+    Reset,
+}
+
+impl Command {
+    fn is_note_on(&self) -> bool {
+        matches!(self, Command::ToneUpdate(tone) if tone.vel != 0)
+    }
+}
+
+#[derive(Debug)]
+struct QueuedWrite {
+    timestamp: Instant,
+    // the track the write comes from, 0 for writes that don't come from a track
+    source: usize,
+    command: Command,
+}
+
+/// Queues writes for the writer task that owns the device. Every write carries the time it was
+/// scheduled for and the track it comes from, which the writer uses to order writes that were
+/// queued at the same time.
+#[derive(Clone)]
+pub struct DeviceHandle {
+    tx: mpsc::Sender<QueuedWrite>,
+    source: usize,
+}
+
+impl DeviceHandle {
+    /// A handle whose writes are ordered as coming from the track with the given index
+    pub fn for_track(&self, track_index: usize) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            source: track_index,
+        }
+    }
+
+    /// Whether the writer task has ended, which it only does before every handle is gone when
+    /// a write failed
+    pub fn writer_stopped(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    pub async fn tone_update(
+        &self,
+        timestamp: Instant,
+        tone: Tone,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.send(timestamp, Command::ToneUpdate(tone)).await
+    }
+
+    pub async fn reset(
+        &self,
+        timestamp: Instant,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.send(timestamp, Command::Reset).await
+    }
+
+    async fn send(
+        &self,
+        timestamp: Instant,
+        command: Command,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.tx
+            .send(QueuedWrite {
+                timestamp,
+                source: self.source,
+                command,
+            })
+            .await
+            .map_err(|_| "the device writer has stopped".into())
+    }
+}
+
+pub type WriterHandle =
+    JoinHandle<Result<Option<WriteStats>, Box<dyn std::error::Error + Send + Sync>>>;
+
+/// Moves the device into its own task which performs all writes to it, so that playback and
+/// the arpeggiators never wait on each other's writes. The task ends with the first failed write, or once every
+/// handle has been dropped, returning what the device counted of its writes.
+pub fn spawn_writer(
+    device: BoxedDevice,
+    zero_velocity: ZeroVelocity,
+    soft_reset: Option<Duration>,
+) -> (DeviceHandle, WriterHandle) {
+    let (tx, rx) = mpsc::channel(256);
+
+    (
+        DeviceHandle { tx, source: 0 },
+        tokio::spawn(run_writer(device, rx, zero_velocity, soft_reset)),
+    )
+}
+
+async fn run_writer(
+    mut device: BoxedDevice,
+    mut rx: mpsc::Receiver<QueuedWrite>,
+    zero_velocity: ZeroVelocity,
+    soft_reset: Option<Duration>,
+) -> Result<Option<WriteStats>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut batch = Vec::new();
+    // (frequency, voice) -> the tones that are sounding, for --soft-reset
+    let mut sounding = HashMap::new();
+
+    while let Some(first) = rx.recv().await {
+        batch.push(first);
+        // the tasks woken by the same timer tick queue their writes in no particular order, so
+        // they get to run once before the writes that are queued are taken. Nothing is waited
+        // for beyond that, a lone write goes out right away
+        tokio::task::yield_now().await;
+        while let Ok(next) = rx.try_recv() {
+            batch.push(next);
+        }
+
+        // writes for the same time go out by track index, note offs before note ons, so that
+        // runs are reproducible. Stable, so the rest keep the order they were queued in
+        batch.sort_by_key(|write| (write.timestamp, write.source, write.command.is_note_on()));
+
+        for QueuedWrite { command, .. } in batch.drain(..) {
+            match command {
+                Command::ToneUpdate(tone) => {
+                    if tone.vel != 0 {
+                        sounding.insert((tone.frequency, tone.voice), tone);
+                    } else {
+                        sounding.remove(&(tone.frequency, tone.voice));
+                    }
+
+                    write_tone(&mut device, tone, zero_velocity).await?
+                }
+                Command::Reset => {
+                    if let Some(duration) = soft_reset {
+                        fade_out(&mut device, &sounding, duration).await?;
+                    }
+                    sounding.clear();
+
+                    device.reset().await?
+                }
+            }
+        }
     }
+
+    Ok(device.write_stats())
+}
+
+async fn write_tone(
+    device: &mut BoxedDevice,
+    tone: Tone,
+    zero_velocity: ZeroVelocity,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match tone {
+        Tone {
+            frequency,
+            vel,
+            duty,
+            voice: Some(voice),
+            ..
+        } => {
+            device
+                .tone_update_on_voice(voice, frequency, vel, duty.unwrap_or(50))
+                .await
+        }
+        Tone {
+            vel,
+            duty,
+            centihertz: Some(centihertz),
+            ..
+        } if vel != 0 || zero_velocity == ZeroVelocity::SustainSilent => {
+            device.tone_update_centihertz(centihertz, vel, duty).await
+        }
+        Tone {
+            centihertz: Some(centihertz),
+            release_vel,
+            ..
+        } => device.note_off_centihertz(centihertz, release_vel).await,
+        Tone {
+            frequency,
+            vel,
+            duty: Some(duty),
+            ..
+        } if vel != 0 => device.tone_update_with_duty(frequency, vel, duty).await,
+        Tone {
+            frequency,
+            vel: 0,
+            release_vel,
+            ..
+        } if zero_velocity == ZeroVelocity::NoteOff => match release_vel {
+            Some(release_vel) => device.note_off_with_velocity(frequency, release_vel).await,
+            None => device.note_off(frequency).await,
+        },
+        Tone { frequency, vel, .. } => device.tone_update(frequency, vel).await,
+    }
+}
+
+// how often the velocity of the sounding tones is lowered while fading them out
+const FADE_STEP: Duration = Duration::from_millis(10);
+
+// lowers the velocity and duty cycle of the sounding tones to almost nothing over `duration`,
+// for --soft-reset. The device has to be able to change the velocity of a sounding tone without
+// re-triggering it
+async fn fade_out(
+    device: &mut BoxedDevice,
+    sounding: &HashMap<(u16, Option<u8>), Tone>,
+    duration: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if sounding.is_empty() {
+        return Ok(());
+    }
+
+    debug!("fading out {} tone(s)", sounding.len());
+
+    let steps = (duration.as_secs_f64() / FADE_STEP.as_secs_f64()).ceil() as u32;
+    let start = Instant::now();
+    for step in 1..steps {
+        tokio::time::sleep_until(start + FADE_STEP * step).await;
+
+        let remaining = 1.0 - step as f64 / steps as f64;
+        let scale = |value: u8| ((value as f64 * remaining).round() as u8).max(1);
+        for tone in sounding.values() {
+            let faded = Tone {
+                vel: scale(tone.vel),
+                duty: tone.duty.map(scale),
+                ..*tone
+            };
+            // a tone update with a velocity, so that it doesn't turn into a note off
+            write_tone(device, faded, ZeroVelocity::SustainSilent).await?;
+        }
+    }
+
+    Ok(())
 }
 
 #[async_trait]
@@ -52,105 +377,577 @@ pub trait Device {
         vel: u8,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
 
+    /// Sounds a tone with its output high for `duty` percent of the period, devices that can't
+    /// change the duty cycle play it as a regular tone
+    async fn tone_update_with_duty(
+        &mut self,
+        frequency: u16,
+        vel: u8,
+        _duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.tone_update(frequency, vel).await
+    }
+
+    /// Sounds a tone given in hundredths of a hertz, devices that only take whole hertz sound
+    /// it at those
+    async fn tone_update_centihertz(
+        &mut self,
+        centihertz: u32,
+        vel: u8,
+        duty: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let frequency = centihertz_to_hertz(centihertz);
+        match duty {
+            Some(duty) => self.tone_update_with_duty(frequency, vel, duty).await,
+            None => self.tone_update(frequency, vel).await,
+        }
+    }
+
+    async fn note_off(
+        &mut self,
+        frequency: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.tone_update(frequency, 0).await
+    }
+
+    /// Stops a tone sounded by `tone_update_centihertz`, along with the velocity it was released
+    /// with if any. Devices that sound whole hertz stop it by those
+    async fn note_off_centihertz(
+        &mut self,
+        centihertz: u32,
+        release_vel: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let frequency = centihertz_to_hertz(centihertz);
+        match release_vel {
+            Some(release_vel) => self.note_off_with_velocity(frequency, release_vel).await,
+            None => self.note_off(frequency).await,
+        }
+    }
+
+    /// Stops a tone with the velocity it was released with, devices without release dynamics
+    /// stop it as usual
+    async fn note_off_with_velocity(
+        &mut self,
+        frequency: u16,
+        _release_vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.note_off(frequency).await
+    }
+
+    /// Sounds or stops a tone on the given voice instead of one the device picks, devices that
+    /// can't address voices pick one as usual
+    async fn tone_update_on_voice(
+        &mut self,
+        _voice: u8,
+        frequency: u16,
+        vel: u8,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if vel == 0 {
+            self.tone_update(frequency, 0).await
+        } else {
+            self.tone_update_with_duty(frequency, vel, duty).await
+        }
+    }
+
     async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// What was written to the device so far, if it keeps count
+    fn write_stats(&self) -> Option<WriteStats> {
+        None
+    }
+
     async fn verify_id(
         &mut self,
     ) -> Result<Result<(), [u8; 4]>, Box<dyn std::error::Error + Send + Sync>>;
 }
 
 const MAGIC_ID: [u8; 4] = [0x61, 0xd8, 0x6e, 0x1c];
-pub struct SerialDevice(SerialStream);
 
-impl SerialDevice {
-    pub async fn new(
-        baud_rate: u32,
-        ignore_id: bool,
-    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let ports = tokio_serial::available_ports()?;
+/// The baud rates accepted by --baudrate, the lowest standard rate up to what USB serial
+/// adapters commonly reach
+pub const BAUD_RATE_RANGE: std::ops::RangeInclusive<u32> = 300..=4_000_000;
 
-        println!("listing available serial ports...");
+// rates that serial adapters and the firmware's UART are known to handle, others may be rounded
+// to something the other end doesn't match
+const STANDARD_BAUD_RATES: [u32; 18] = [
+    300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 38400, 57600, 115200, 230400, 250000, 460800,
+    500000, 921600, 1000000, 2000000,
+];
 
-        ports
-            .iter()
-            .enumerate()
-            .for_each(|(i, p)| println!("{}: {}", i, p.port_name.split('/').last().unwrap()));
+fn format_id(id: &[u8; 4]) -> String {
+    id.iter().map(|byte| format!("{byte:X}")).collect()
+}
+
+/// Counts the writes to a serial device, to compare the load on the link with what its baud rate
+/// allows
+#[derive(Debug, Clone, Copy)]
+pub struct WriteStats {
+    pub baud_rate: u32,
+    pub bytes: u64,
+    pub messages: u64,
+    pub elapsed: Duration,
+    // the most bytes written within one second
+    pub peak_bytes_per_second: u64,
+    window_start: Instant,
+    window_bytes: u64,
+}
 
-        if ports.is_empty() {
-            println!("no available serial ports");
-            std::process::exit(1);
+impl WriteStats {
+    fn new(baud_rate: u32) -> Self {
+        Self {
+            baud_rate,
+            bytes: 0,
+            messages: 0,
+            elapsed: Duration::ZERO,
+            peak_bytes_per_second: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
         }
+    }
 
-        let selection: usize = {
-            if ports.len() == 1 {
-                0
-            } else {
-                read_input("selection: ", FromStr::from_str, |n| *n < ports.len())?
-            }
-        };
+    fn record(&mut self, opened: Instant, bytes: usize) {
+        let now = Instant::now();
+        if now - self.window_start >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.window_bytes = 0;
+        }
 
-        #[cfg(target_family = "unix")]
-        let (dev_name, dev_path) = {
-            let dev_name = ports[selection].port_name.split('/').last().unwrap();
-            let dev_path: PathBuf = ["/dev", dev_name].iter().collect();
+        self.bytes += bytes as u64;
+        self.messages += 1;
+        self.window_bytes += bytes as u64;
+        self.peak_bytes_per_second = self.peak_bytes_per_second.max(self.window_bytes);
+        self.elapsed = now - opened;
+    }
 
-            (dev_name, dev_path)
-        };
-        #[cfg(target_family = "windows")]
-        let (dev_name, dev_path) = {
-            let dev_name = ports[selection].port_name.clone();
-            let dev_path: PathBuf = dev_name.clone().into();
+    // every byte takes 10 bits on the line, with its start and stop bit
+    fn bytes_per_second_capacity(&self) -> f64 {
+        self.baud_rate as f64 / 10.0
+    }
 
-            (dev_name, dev_path)
-        };
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(1.0)
+    }
 
-        println!("selected device {dev_name}");
+    pub fn messages_per_second(&self) -> f64 {
+        self.messages as f64 / self.elapsed.as_secs_f64().max(1.0)
+    }
 
-        println!("baudrate: {baud_rate}");
-        println!("opening device at {}", dev_path.to_string_lossy());
+    /// The fraction of the capacity of the link used on average
+    pub fn utilization(&self) -> f64 {
+        self.bytes_per_second() / self.bytes_per_second_capacity()
+    }
 
-        let mut dev = Self(SerialStream::open(&tokio_serial::new(
-            dev_path.to_string_lossy(),
-            baud_rate,
-        ))?);
+    /// The fraction of the capacity of the link used in the busiest second
+    pub fn peak_utilization(&self) -> f64 {
+        self.peak_bytes_per_second as f64 / self.bytes_per_second_capacity()
+    }
+}
+
+/// Configures and opens a `SerialDevice` without any prompting, errors are returned instead of
+/// exiting
+#[derive(Debug, Clone)]
+pub struct SerialDeviceBuilder {
+    port: Option<PathBuf>,
+    pick_port: bool,
+    baud_rate: u32,
+    device_id: [u8; 4],
+    skip_handshake: bool,
+    ignore_id_mismatch: bool,
+    clear_on_start: bool,
+    parity: tokio_serial::Parity,
+    write_timeout: Duration,
+    id_timeout: Duration,
+    tone_trailer: Option<u8>,
+    freq_scale: FreqScale,
+    note_off_message: bool,
+}
+
+impl Default for SerialDeviceBuilder {
+    fn default() -> Self {
+        Self {
+            port: None,
+            pick_port: false,
+            baud_rate: 250000,
+            device_id: MAGIC_ID,
+            skip_handshake: false,
+            ignore_id_mismatch: false,
+            clear_on_start: true,
+            parity: tokio_serial::Parity::None,
+            write_timeout: Duration::from_secs(1),
+            id_timeout: Duration::from_secs(1),
+            tone_trailer: Some(TONE_TRAILER),
+            freq_scale: FreqScale::Hertz,
+            note_off_message: false,
+        }
+    }
+}
 
-        match dev.verify_id().await {
-            Ok(r) => match r {
+impl SerialDeviceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn port(mut self, port: impl Into<PathBuf>) -> Self {
+        self.port = Some(port.into());
+        self
+    }
+
+    /// Let the user pick the port even when the one picked last time is available, remembering
+    /// the new pick instead
+    pub fn pick_port(mut self, pick_port: bool) -> Self {
+        self.pick_port = pick_port;
+        self
+    }
+
+    pub fn baud(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// The ID the device has to answer the handshake with
+    pub fn device_id(mut self, device_id: [u8; 4]) -> Self {
+        self.device_id = device_id;
+        self
+    }
+
+    pub fn skip_handshake(mut self, skip_handshake: bool) -> Self {
+        self.skip_handshake = skip_handshake;
+        self
+    }
+
+    /// Only warn when the device answers the handshake with another ID
+    pub fn ignore_id_mismatch(mut self, ignore_id_mismatch: bool) -> Self {
+        self.ignore_id_mismatch = ignore_id_mismatch;
+        self
+    }
+
+    /// Reset the device once connected, so that nothing it was sounding before is left on
+    pub fn clear_on_start(mut self, clear_on_start: bool) -> Self {
+        self.clear_on_start = clear_on_start;
+        self
+    }
+
+    pub fn parity(mut self, parity: tokio_serial::Parity) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    /// How long a single write may block before it fails
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    /// How long the device may take to answer the handshake, which firmware that only gets
+    /// to it once it is done starting up can need longer for than any write
+    pub fn id_timeout(mut self, id_timeout: Duration) -> Self {
+        self.id_timeout = id_timeout;
+        self
+    }
+
+    /// The byte that ends tone updates, for firmware revisions that delimit them with another
+    /// byte, or with none at all
+    pub fn tone_trailer(mut self, tone_trailer: Option<u8>) -> Self {
+        self.tone_trailer = tone_trailer;
+        self
+    }
+
+    /// How the frequencies are sent, hundredths of a hertz only reach firmware that takes them
+    pub fn freq_scale(mut self, freq_scale: FreqScale) -> Self {
+        self.freq_scale = freq_scale;
+        self
+    }
+
+    /// Stop tones with note off messages instead of tone updates with velocity 0, which only
+    /// firmware that takes them understands
+    pub fn note_off_message(mut self, note_off_message: bool) -> Self {
+        self.note_off_message = note_off_message;
+        self
+    }
+
+    pub async fn build(self) -> Result<SerialDevice, Box<dyn std::error::Error + Send + Sync>> {
+        let port = self.port.clone().ok_or("no serial port given")?;
+
+        info!("baudrate: {}", self.baud_rate);
+        if !STANDARD_BAUD_RATES.contains(&self.baud_rate) {
+            warn!(
+                "{} is not a standard baud rate, the port or the device may not support it",
+                self.baud_rate
+            );
+        }
+        info!("opening device at {}", port.to_string_lossy());
+
+        let stream = SerialStream::open(
+            &tokio_serial::new(port.to_string_lossy(), self.baud_rate).parity(self.parity),
+        )?;
+
+        self.build_from_stream(stream).await
+    }
+
+    /// Uses an already opened stream instead of opening the port, e.g. one end of a
+    /// `SerialStream::pair` or of a `tokio::io::duplex` whose other end is driven by a test
+    /// harness
+    pub async fn build_from_stream<S: AsyncRead + AsyncWrite + Unpin + Send>(
+        self,
+        stream: S,
+    ) -> Result<SerialDevice<S>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut dev = SerialDevice {
+            stream,
+            id: self.device_id,
+            write_timeout: self.write_timeout,
+            id_timeout: self.id_timeout,
+            opened: Instant::now(),
+            stats: WriteStats::new(self.baud_rate),
+            tone_trailer: self.tone_trailer,
+            freq_scale: self.freq_scale,
+            note_off_message: self.note_off_message,
+        };
+
+        if self.skip_handshake {
+            info!("skipping the ID handshake");
+        } else {
+            match dev
+                .verify_id()
+                .await
+                .map_err(|e| format!("device failed to answer ID: {e}"))?
+            {
                 Ok(_) => {
-                    print!("device answered with correct ID: ");
-                    for byte in MAGIC_ID.iter() {
-                        print!("{:X}", *byte);
-                    }
-                    println!("");
+                    info!("device answered with correct ID: {}", format_id(&dev.id));
+                }
+                Err(response) if self.ignore_id_mismatch => {
+                    warn!(
+                        "device answered with incorrect ID: {}, ignoring",
+                        format_id(&response)
+                    );
                 }
                 Err(response) => {
-                    print!("device answered with incorrect ID: ");
-                    for byte in response.iter() {
-                        print!("{:X}", *byte);
-                    }
-                    println!("");
-                    if ignore_id {
-                        println!("ignoring")
-                    } else {
-                        exit(1);
-                    }
+                    return Err(format!(
+                        "device answered with incorrect ID: {}",
+                        format_id(&response)
+                    )
+                    .into());
+                }
+            }
+
+            // firmware without the centihertz message would take its frames for other messages
+            if self.freq_scale == FreqScale::Centihertz {
+                let features = dev
+                    .features()
+                    .await
+                    .map_err(|e| format!("device failed to answer its features: {e}"))?;
+                if features & FEATURE_CENTIHERTZ == 0 {
+                    return Err(
+                        "the device can't sound frequencies in hundredths of a hertz, \
+                        --freq-scale cHz needs firmware that can"
+                            .into(),
+                    );
                 }
-            },
-            Err(e) => {
-                println!("device failed to answer ID: {e}");
-                exit(1);
             }
         }
 
+        // some firmware powers up with a speaker already sounding
+        if self.clear_on_start {
+            debug!("clearing the device");
+            dev.reset()
+                .await
+                .map_err(|e| format!("failed to clear the device: {e}"))?;
+        }
+
         Ok(dev)
     }
 }
 
+/// A device speaking the protocol over a serial port, or any other stream for testing
+pub struct SerialDevice<S = SerialStream> {
+    stream: S,
+    // the ID expected in the handshake
+    id: [u8; 4],
+    write_timeout: Duration,
+    // how long the answers of the handshake may take
+    id_timeout: Duration,
+    opened: Instant,
+    stats: WriteStats,
+    tone_trailer: Option<u8>,
+    freq_scale: FreqScale,
+    // stops tones with note off messages instead of tone updates with velocity 0
+    note_off_message: bool,
+}
+
+impl<S: AsyncWrite + Unpin> SerialDevice<S> {
+    // a half open connection can block a write without it ever timing out on its own, so every
+    // write is bounded to turn that into an error instead of hanging forever
+    async fn write(
+        &mut self,
+        message: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut num_timed_out = 1;
+        loop {
+            let write = <_ as tokio::io::AsyncWriteExt>::write_all(&mut self.stream, message);
+
+            match tokio::time::timeout(self.write_timeout, write).await {
+                Ok(Ok(_)) => {
+                    self.stats.record(self.opened, message.len());
+                    return Ok(());
+                }
+                Ok(Err(e)) => match e.kind() {
+                    std::io::ErrorKind::TimedOut => warn!("timed out {num_timed_out}"),
+                    _ => return Err(Box::new(e)),
+                },
+                Err(_) => {
+                    return Err(format!(
+                        "device write blocked for more than {} ms",
+                        self.write_timeout.as_millis()
+                    )
+                    .into())
+                }
+            }
+            num_timed_out += 1;
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> SerialDevice<S> {
+    // the bits of the features the firmware has. Firmware from before the message doesn't
+    // answer it, which is taken as none
+    async fn features(&mut self) -> Result<u8, Box<dyn std::error::Error + Send + Sync>> {
+        self.write(&GET_FEATURES_MESSAGE).await?;
+
+        let mut features = [0];
+        let read = <_ as tokio::io::AsyncReadExt>::read(&mut self.stream, &mut features);
+        match tokio::time::timeout(self.id_timeout, read).await {
+            Ok(Ok(0)) => Err("device closed the stream".into()),
+            Ok(Ok(_)) => Ok(features[0]),
+            Ok(Err(e)) => Err(Box::new(e)),
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+impl SerialDevice {
+    /// Opens the port of the builder, or lets the user pick one of the available ports if it
+    /// has none, exiting on failure. A picked port that is gone by the time it is opened is
+    /// picked again.
+    pub async fn new(
+        builder: SerialDeviceBuilder,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let picked = builder.port.is_none();
+
+        loop {
+            let port = match &builder.port {
+                Some(port) => port.clone(),
+                None => select_port(builder.pick_port)?,
+            };
+
+            match builder.clone().port(port.clone()).build().await {
+                Ok(dev) => return Ok(dev),
+                Err(e) if !port_available(&port) => {
+                    if picked {
+                        warn!("selected port no longer available ({e}), listing the ports again");
+                        continue;
+                    }
+
+                    error!(
+                        "selected port {} no longer available: {e}",
+                        port.to_string_lossy()
+                    );
+                    exit(1);
+                }
+                Err(e) => {
+                    error!("{e}");
+                    exit(1);
+                }
+            }
+        }
+    }
+}
+
+// whether the port is still among the available ports, as adapters on flaky USB hubs can vanish
+// between listing the ports and opening one. Assumed to be there if the ports can't be listed
+fn port_available(port: &Path) -> bool {
+    match tokio_serial::available_ports() {
+        Ok(ports) => ports
+            .iter()
+            .any(|info| Path::new(&info.port_name).file_name() == port.file_name()),
+        Err(_) => true,
+    }
+}
+
+const LAST_PORT_STATE: &str = "last_port";
+
+// identifies usb adapters by what they report about themselves, as their port names and
+// the order they are listed in can change between runs
+fn port_identity(port: &SerialPortInfo) -> String {
+    match &port.port_type {
+        SerialPortType::UsbPort(UsbPortInfo {
+            vid,
+            pid,
+            serial_number: Some(serial_number),
+            ..
+        }) => format!("usb:{vid:04x}:{pid:04x}:{serial_number}"),
+        _ => format!("name:{}", port.port_name),
+    }
+}
+
+fn select_port(pick: bool) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+    let ports = list_ports()?;
+
+    if ports.is_empty() {
+        error!("no available serial ports");
+        std::process::exit(1);
+    }
+
+    let last_port = state::load(LAST_PORT_STATE).filter(|_| !pick);
+    let remembered = last_port.as_ref().and_then(|last_port| {
+        ports
+            .iter()
+            .position(|port| port_identity(port) == *last_port)
+    });
+
+    let selection: usize = {
+        if ports.len() == 1 {
+            0
+        } else if let Some(remembered) = remembered {
+            info!("using the port selected last time, --pick-port to choose another");
+            remembered
+        } else {
+            read_input("selection: ", FromStr::from_str, |n| *n < ports.len())?
+        }
+    };
+
+    state::save(LAST_PORT_STATE, &port_identity(&ports[selection]));
+
+    #[cfg(target_family = "unix")]
+    let (dev_name, dev_path) = {
+        let dev_name = ports[selection].port_name.split('/').next_back().unwrap();
+        let dev_path: PathBuf = ["/dev", dev_name].iter().collect();
+
+        (dev_name, dev_path)
+    };
+    #[cfg(target_family = "windows")]
+    let (dev_name, dev_path) = {
+        let dev_name = ports[selection].port_name.clone();
+        let dev_path: PathBuf = dev_name.clone().into();
+
+        (dev_name, dev_path)
+    };
+
+    info!("selected device {dev_name}");
+
+    Ok(dev_path)
+}
+
 /* message format sent to device
 big endian transmission format
 first byte: message type
 0x01 : tone update
 0x02 : reset
 0x03 : get id
+0x04 : tone update with duty cycle
+0x05 : tone update on a voice
+0x06 : note off
+0x07 : note off with release velocity
+0x08 : tone update in hundredths of a hertz
 
 tone update message layout
 01 xx xx yy 01
@@ -158,6 +955,9 @@ tone update message layout
 x: u16 tone
 y: u16 velocity
 
+the trailing 01 only delimits the frame. Firmware revisions that expect another byte there, or
+none, are given it with --tone-trailer or --no-tone-trailer
+
 reset message layout
 
 02
@@ -170,50 +970,224 @@ RESPONSE: 4 bytes
 
 61 d8 6e 1c
 
+tone update with duty cycle message layout
+
+04 xx xx yy zz 04
+
+x: u16 tone
+y: u8 velocity
+z: u8 duty cycle in percent of the period
+
+tone update on a voice message layout
+
+05 nn xx xx yy zz 05
+
+n: u8 index of the voice
+x: u16 tone
+y: u8 velocity
+z: u8 duty cycle in percent of the period
+
+note off message layout
+
+06 xx xx 06
+
+x: u16 tone
+
+note off with release velocity message layout
+
+07 xx xx rr 07
+
+x: u16 tone
+r: u8 release velocity
+
+tone update in hundredths of a hertz message layout
+
+08 cc cc cc cc yy zz 08
+
+c: u32 tone in hundredths of a hertz
+y: u8 velocity
+z: u8 duty cycle in percent of the period
+
+only sent with --freq-scale cHz. The tone is stopped by its whole hertz like any other
+
  */
 
+const RESET_MESSAGE: [u8; 1] = [0x02];
+const GET_ID_MESSAGE: [u8; 1] = [0x03];
+const GET_FEATURES_MESSAGE: [u8; 1] = [0x09];
+
+// the bits of the features the firmware answers with
+const FEATURE_CENTIHERTZ: u8 = 0x01;
+
+// the byte tone updates end with unless the firmware expects another one
+const TONE_TRAILER: u8 = 0x01;
+
+fn tone_update_message(freq: u16, vel: u8, trailer: Option<u8>) -> Vec<u8> {
+    let freq = freq.to_be_bytes();
+    let mut message = vec![0x01, freq[0], freq[1], vel];
+    message.extend(trailer);
+    message
+}
+
+fn duty_update_message(freq: u16, vel: u8, duty: u8) -> [u8; 6] {
+    let freq = freq.to_be_bytes();
+    [0x04, freq[0], freq[1], vel, duty, 0x04]
+}
+
+fn note_off_message(freq: u16) -> [u8; 4] {
+    let freq = freq.to_be_bytes();
+    [0x06, freq[0], freq[1], 0x06]
+}
+
+fn release_message(freq: u16, release_vel: u8) -> [u8; 5] {
+    let freq = freq.to_be_bytes();
+    [0x07, freq[0], freq[1], release_vel, 0x07]
+}
+
+fn voice_update_message(voice: u8, freq: u16, vel: u8, duty: u8) -> [u8; 7] {
+    let freq = freq.to_be_bytes();
+    [0x05, voice, freq[0], freq[1], vel, duty, 0x05]
+}
+
+fn centihertz_update_message(centihertz: u32, vel: u8, duty: u8) -> [u8; 8] {
+    let c = centihertz.to_be_bytes();
+    [0x08, c[0], c[1], c[2], c[3], vel, duty, 0x08]
+}
+
 #[async_trait]
-impl Device for SerialDevice {
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Device for SerialDevice<S> {
     async fn tone_update(
         &mut self,
         freq: u16,
         vel: u8,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let freq = freq.to_be_bytes();
+        self.write(&tone_update_message(freq, vel, self.tone_trailer))
+            .await
+    }
 
-        let message: [u8; 5] = [0x01, freq[0], freq[1], vel, 0x01];
-        let mut num_timed_out = 1;
-        loop {
-            match <_ as tokio::io::AsyncWriteExt>::write_all(&mut self.0, &message).await {
-                Ok(_) => return Ok(()),
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::TimedOut => eprintln!("timed out {num_timed_out}"),
-                    _ => return Err(Box::new(e)),
-                },
+    async fn tone_update_with_duty(
+        &mut self,
+        freq: u16,
+        vel: u8,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.write(&duty_update_message(freq, vel, duty)).await
+    }
+
+    async fn tone_update_centihertz(
+        &mut self,
+        centihertz: u32,
+        vel: u8,
+        duty: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let frequency = centihertz_to_hertz(centihertz);
+        match (self.freq_scale, duty) {
+            (FreqScale::Centihertz, _) => {
+                self.write(&centihertz_update_message(
+                    centihertz,
+                    vel,
+                    duty.unwrap_or(50),
+                ))
+                .await
             }
-            num_timed_out += 1;
+            (FreqScale::Hertz, Some(duty)) => {
+                self.tone_update_with_duty(frequency, vel, duty).await
+            }
+            (FreqScale::Hertz, None) => self.tone_update(frequency, vel).await,
         }
     }
 
-    async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        let message: [u8; 1] = [0x2];
+    async fn note_off(
+        &mut self,
+        freq: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !self.note_off_message {
+            return self.tone_update(freq, 0).await;
+        }
+
+        self.write(&note_off_message(freq)).await
+    }
+
+    async fn note_off_with_velocity(
+        &mut self,
+        freq: u16,
+        release_vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.write(&release_message(freq, release_vel)).await
+    }
+
+    async fn note_off_centihertz(
+        &mut self,
+        centihertz: u32,
+        release_vel: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // the firmware stops the speaker sounding the exact frequency
+        if self.freq_scale == FreqScale::Centihertz {
+            return self
+                .write(&centihertz_update_message(centihertz, 0, 0))
+                .await;
+        }
+
+        let frequency = centihertz_to_hertz(centihertz);
+        match release_vel {
+            Some(release_vel) => self.note_off_with_velocity(frequency, release_vel).await,
+            None => self.note_off(frequency).await,
+        }
+    }
 
-        <_ as tokio::io::AsyncWriteExt>::write_all(&mut self.0, &message)
+    async fn tone_update_on_voice(
+        &mut self,
+        voice: u8,
+        freq: u16,
+        vel: u8,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.write(&voice_update_message(voice, freq, vel, duty))
             .await
-            .map_err(|e| e.into())
+    }
+
+    fn write_stats(&self) -> Option<WriteStats> {
+        Some(self.stats)
+    }
+
+    async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.write(&RESET_MESSAGE).await
     }
 
     async fn verify_id(
         &mut self,
     ) -> Result<Result<(), [u8; 4]>, Box<dyn std::error::Error + Send + Sync>> {
-        let message: [u8; 1] = [0x3];
-
         let mut buf: [u8; 4] = [0; 4];
 
-        <_ as tokio::io::AsyncWriteExt>::write_all(&mut self.0, &message).await?;
-        <_ as tokio::io::AsyncReadExt>::read_exact(&mut self.0, &mut buf).await?;
+        self.write(&GET_ID_MESSAGE).await?;
+
+        // read piecewise rather than with read_exact to know how much of the ID came when the
+        // device stalls
+        let deadline = Instant::now() + self.id_timeout;
+        let mut received = 0;
+        while received < buf.len() {
+            let read = tokio::time::timeout_at(
+                deadline,
+                <_ as tokio::io::AsyncReadExt>::read(&mut self.stream, &mut buf[received..]),
+            )
+            .await
+            .map_err(|_| {
+                format!(
+                    "device sent {received} of 4 ID bytes before timeout ({} ms)",
+                    self.id_timeout.as_millis()
+                )
+            })??;
+
+            if read == 0 {
+                return Err(format!(
+                    "device sent {received} of 4 ID bytes before closing the stream"
+                )
+                .into());
+            }
+            received += read;
+        }
 
-        if buf == MAGIC_ID {
+        if buf == self.id {
             Ok(Ok(()))
         } else {
             Ok(Err(buf))
@@ -221,25 +1195,404 @@ impl Device for SerialDevice {
     }
 }
 
-pub struct DummyDevice;
+/// Accepts every write without a device. For --dump-protocol it prints the messages a serial
+/// device would be sent instead, as hex along with the time since it was opened.
+#[derive(Default)]
+pub struct DummyDevice {
+    // set when dumping the protocol
+    dump_start: Option<Instant>,
+    // the trailer of the dumped tone updates
+    tone_trailer: Option<u8>,
+    // how the frequencies of the dumped tone updates are sent
+    freq_scale: FreqScale,
+    // whether tones are stopped with note off messages
+    note_off_message: bool,
+}
+
+impl DummyDevice {
+    pub fn dumping_protocol(
+        tone_trailer: Option<u8>,
+        freq_scale: FreqScale,
+        note_off_message: bool,
+    ) -> Self {
+        Self {
+            dump_start: Some(Instant::now()),
+            tone_trailer,
+            freq_scale,
+            note_off_message,
+        }
+    }
+
+    fn dump(&self, message: &[u8]) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let Some(start) = self.dump_start else {
+            return Ok(());
+        };
+
+        let bytes = message
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(
+            std::io::stdout().lock(),
+            "{:>9.3} s  {bytes}",
+            start.elapsed().as_secs_f64()
+        )?;
+
+        Ok(())
+    }
+}
 
 #[async_trait]
 impl Device for DummyDevice {
     async fn tone_update(
         &mut self,
-        _: u16,
-        _: u8,
+        freq: u16,
+        vel: u8,
     ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        Ok(())
+        self.dump(&tone_update_message(freq, vel, self.tone_trailer))
+    }
+
+    async fn tone_update_with_duty(
+        &mut self,
+        freq: u16,
+        vel: u8,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.dump(&duty_update_message(freq, vel, duty))
+    }
+
+    async fn tone_update_centihertz(
+        &mut self,
+        centihertz: u32,
+        vel: u8,
+        duty: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let frequency = centihertz_to_hertz(centihertz);
+        match (self.freq_scale, duty) {
+            (FreqScale::Centihertz, _) => self.dump(&centihertz_update_message(
+                centihertz,
+                vel,
+                duty.unwrap_or(50),
+            )),
+            (FreqScale::Hertz, Some(duty)) => self.dump(&duty_update_message(frequency, vel, duty)),
+            (FreqScale::Hertz, None) => {
+                self.dump(&tone_update_message(frequency, vel, self.tone_trailer))
+            }
+        }
+    }
+
+    async fn note_off(
+        &mut self,
+        freq: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if !self.note_off_message {
+            return self.tone_update(freq, 0).await;
+        }
+
+        self.dump(&note_off_message(freq))
+    }
+
+    async fn note_off_with_velocity(
+        &mut self,
+        freq: u16,
+        release_vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.dump(&release_message(freq, release_vel))
+    }
+
+    async fn note_off_centihertz(
+        &mut self,
+        centihertz: u32,
+        release_vel: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.freq_scale == FreqScale::Centihertz {
+            return self.dump(&centihertz_update_message(centihertz, 0, 0));
+        }
+
+        let frequency = centihertz_to_hertz(centihertz);
+        match release_vel {
+            Some(release_vel) => self.note_off_with_velocity(frequency, release_vel).await,
+            None => self.note_off(frequency).await,
+        }
+    }
+
+    async fn tone_update_on_voice(
+        &mut self,
+        voice: u8,
+        freq: u16,
+        vel: u8,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.dump(&voice_update_message(voice, freq, vel, duty))
     }
 
     async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-        Ok(())
+        self.dump(&RESET_MESSAGE)
     }
 
     async fn verify_id(
         &mut self,
     ) -> Result<Result<(), [u8; 4]>, Box<dyn std::error::Error + Send + Sync>> {
+        self.dump(&GET_ID_MESSAGE)?;
         Ok(Ok(()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    // a device over one end of an in-memory stream, the other end stands in for the firmware
+    fn connected() -> (SerialDeviceBuilder, DuplexStream, DuplexStream) {
+        let (device_end, firmware_end) = tokio::io::duplex(64);
+        let builder = SerialDeviceBuilder::new()
+            .write_timeout(Duration::from_millis(50))
+            .id_timeout(Duration::from_millis(50));
+        (builder, device_end, firmware_end)
+    }
+
+    async fn read_bytes(firmware_end: &mut DuplexStream, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        firmware_end.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn handshake_accepts_id() {
+        let (builder, device_end, mut firmware_end) = connected();
+
+        let firmware = tokio::spawn(async move {
+            assert_eq!(read_bytes(&mut firmware_end, 1).await, GET_ID_MESSAGE);
+            firmware_end.write_all(&MAGIC_ID).await.unwrap();
+            // cleared once connected
+            assert_eq!(read_bytes(&mut firmware_end, 1).await, RESET_MESSAGE);
+        });
+
+        assert!(builder.build_from_stream(device_end).await.is_ok());
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_waits_longer_than_a_write() {
+        let (builder, device_end, mut firmware_end) = connected();
+        let builder = builder.id_timeout(Duration::from_secs(2));
+
+        let firmware = tokio::spawn(async move {
+            read_bytes(&mut firmware_end, 1).await;
+            // still starting up for longer than a write may block
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            firmware_end.write_all(&MAGIC_ID).await.unwrap();
+            assert_eq!(read_bytes(&mut firmware_end, 1).await, RESET_MESSAGE);
+        });
+
+        assert!(builder.build_from_stream(device_end).await.is_ok());
+        firmware.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_rejects_other_id() {
+        let (builder, device_end, mut firmware_end) = connected();
+
+        tokio::spawn(async move {
+            read_bytes(&mut firmware_end, 1).await;
+            firmware_end.write_all(&[1, 2, 3, 4]).await.unwrap();
+            // keeps the stream open
+            read_bytes(&mut firmware_end, 1).await;
+        });
+
+        let error = builder.build_from_stream(device_end).await.err().unwrap();
+        assert_eq!(error.to_string(), "device answered with incorrect ID: 1234");
+    }
+
+    #[tokio::test]
+    async fn handshake_times_out_on_partial_id() {
+        let (builder, device_end, mut firmware_end) = connected();
+
+        tokio::spawn(async move {
+            read_bytes(&mut firmware_end, 1).await;
+            firmware_end.write_all(&MAGIC_ID[..2]).await.unwrap();
+            read_bytes(&mut firmware_end, 1).await;
+        });
+
+        let error = builder.build_from_stream(device_end).await.err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "device failed to answer ID: device sent 2 of 4 ID bytes before timeout (50 ms)"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_asks_for_centihertz() {
+        for (answer, accepted) in [
+            (Some(FEATURE_CENTIHERTZ), true),
+            (Some(0), false),
+            (None, false),
+        ] {
+            let (builder, device_end, mut firmware_end) = connected();
+
+            let firmware = tokio::spawn(async move {
+                read_bytes(&mut firmware_end, 1).await;
+                firmware_end.write_all(&MAGIC_ID).await.unwrap();
+                assert_eq!(read_bytes(&mut firmware_end, 1).await, GET_FEATURES_MESSAGE);
+                // firmware from before the message doesn't answer it
+                if let Some(answer) = answer {
+                    firmware_end.write_all(&[answer]).await.unwrap();
+                }
+                firmware_end
+            });
+
+            let built = builder
+                .freq_scale(FreqScale::Centihertz)
+                .clear_on_start(false)
+                .build_from_stream(device_end)
+                .await;
+            assert_eq!(built.is_ok(), accepted);
+            firmware.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn centihertz_frames_tell_low_semitones_apart() {
+        let (builder, device_end, mut firmware_end) = connected();
+        let device = builder
+            .skip_handshake(true)
+            .clear_on_start(false)
+            .freq_scale(FreqScale::Centihertz)
+            .build_from_stream(device_end)
+            .await
+            .unwrap();
+        let (device, writer) = spawn_writer(Box::new(device), ZeroVelocity::NoteOff, None);
+
+        // the two lowest keys an octave down, which are both 8 Hz in whole hertz
+        let tone = |centihertz, vel| Tone {
+            frequency: centihertz_to_hertz(centihertz),
+            vel,
+            duty: None,
+            voice: None,
+            release_vel: None,
+            centihertz: Some(centihertz),
+        };
+        device
+            .tone_update(Instant::now(), tone(817, 100))
+            .await
+            .unwrap();
+        device
+            .tone_update(Instant::now(), tone(866, 100))
+            .await
+            .unwrap();
+        device
+            .tone_update(Instant::now(), tone(817, 0))
+            .await
+            .unwrap();
+        drop(device);
+        writer.await.unwrap().unwrap();
+
+        assert_eq!(
+            read_bytes(&mut firmware_end, 24).await,
+            [
+                [0x08, 0x00, 0x00, 0x03, 0x31, 0x64, 0x32, 0x08],
+                [0x08, 0x00, 0x00, 0x03, 0x62, 0x64, 0x32, 0x08],
+                [0x08, 0x00, 0x00, 0x03, 0x31, 0x00, 0x00, 0x08],
+            ]
+            .concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_fails_on_closed_stream() {
+        let (builder, device_end, mut firmware_end) = connected();
+
+        tokio::spawn(async move {
+            read_bytes(&mut firmware_end, 1).await;
+            firmware_end.write_all(&MAGIC_ID[..3]).await.unwrap();
+        });
+
+        let error = builder.build_from_stream(device_end).await.err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "device failed to answer ID: device sent 3 of 4 ID bytes before closing the stream"
+        );
+    }
+
+    #[tokio::test]
+    async fn tone_frames() {
+        let (builder, device_end, mut firmware_end) = connected();
+        let mut device = builder
+            .skip_handshake(true)
+            .clear_on_start(false)
+            .build_from_stream(device_end)
+            .await
+            .unwrap();
+
+        device.tone_update(440, 100).await.unwrap();
+        assert_eq!(
+            read_bytes(&mut firmware_end, 5).await,
+            [0x01, 0x01, 0xb8, 0x64, 0x01]
+        );
+
+        // a velocity 0 tone update unless note off messages are asked for
+        device.note_off(440).await.unwrap();
+        assert_eq!(
+            read_bytes(&mut firmware_end, 5).await,
+            [0x01, 0x01, 0xb8, 0x00, 0x01]
+        );
+
+        device.tone_update_with_duty(440, 100, 25).await.unwrap();
+        assert_eq!(
+            read_bytes(&mut firmware_end, 6).await,
+            [0x04, 0x01, 0xb8, 0x64, 0x19, 0x04]
+        );
+    }
+
+    #[tokio::test]
+    async fn writer_stops_on_failed_write() {
+        let (builder, device_end, firmware_end) = connected();
+        let device = builder
+            .skip_handshake(true)
+            .clear_on_start(false)
+            .build_from_stream(device_end)
+            .await
+            .unwrap();
+        drop(firmware_end);
+
+        let (device, writer) = spawn_writer(Box::new(device), ZeroVelocity::NoteOff, None);
+        device
+            .tone_update(Instant::now(), Tone::off(440))
+            .await
+            .unwrap();
+
+        // the error the writer stopped with is the one to report, not the failed sends
+        let e = writer.await.unwrap().unwrap_err();
+        assert!(e.to_string() != "the device writer has stopped");
+        assert!(device.writer_stopped());
+        assert!(device.reset(Instant::now()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tone_frames_with_trailer_and_note_off() {
+        let (builder, device_end, mut firmware_end) = connected();
+        let mut device = builder
+            .skip_handshake(true)
+            .clear_on_start(false)
+            .tone_trailer(None)
+            .note_off_message(true)
+            .build_from_stream(device_end)
+            .await
+            .unwrap();
+
+        device.tone_update(440, 100).await.unwrap();
+        assert_eq!(
+            read_bytes(&mut firmware_end, 4).await,
+            [0x01, 0x01, 0xb8, 0x64]
+        );
+
+        device.note_off(440).await.unwrap();
+        assert_eq!(
+            read_bytes(&mut firmware_end, 4).await,
+            [0x06, 0x01, 0xb8, 0x06]
+        );
+    }
+}
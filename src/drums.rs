@@ -0,0 +1,100 @@
+use std::{collections::HashMap, path::Path};
+
+// fixed frequencies for the General MIDI percussion keys, low for drums and toms, high for
+// cymbals and hi-hats, which a buzzer can't play as noise
+const GM_DRUMS: [(u8, u16); 40] = [
+    (35, 50),   // acoustic bass drum
+    (36, 60),   // bass drum 1
+    (37, 1200), // side stick
+    (38, 200),  // acoustic snare
+    (39, 1000), // hand clap
+    (40, 220),  // electric snare
+    (41, 90),   // low floor tom
+    (42, 6000), // closed hi-hat
+    (43, 110),  // high floor tom
+    (44, 5500), // pedal hi-hat
+    (45, 130),  // low tom
+    (46, 5000), // open hi-hat
+    (47, 150),  // low-mid tom
+    (48, 175),  // hi-mid tom
+    (49, 4500), // crash cymbal 1
+    (50, 200),  // high tom
+    (51, 3500), // ride cymbal 1
+    (52, 4000), // chinese cymbal
+    (53, 2500), // ride bell
+    (54, 7000), // tambourine
+    (55, 4800), // splash cymbal
+    (56, 800),  // cowbell
+    (57, 4200), // crash cymbal 2
+    (58, 900),  // vibraslap
+    (59, 3300), // ride cymbal 2
+    (60, 400),  // hi bongo
+    (61, 300),  // low bongo
+    (62, 350),  // mute hi conga
+    (63, 330),  // open hi conga
+    (64, 250),  // low conga
+    (65, 280),  // high timbale
+    (66, 230),  // low timbale
+    (67, 700),  // high agogo
+    (68, 600),  // low agogo
+    (69, 6500), // cabasa
+    (70, 6800), // maracas
+    (75, 2500), // claves
+    (76, 1800), // hi wood block
+    (77, 1400), // low wood block
+    (81, 8000), // open triangle
+];
+
+/// Maps the keys of a percussion track to the frequency they are played at, keys without a
+/// frequency are not played
+pub type DrumMap = HashMap<u8, u16>;
+
+pub fn gm_drum_map() -> DrumMap {
+    GM_DRUMS.into_iter().collect()
+}
+
+/// The General MIDI map with the `key frequency` lines of the file on top. A frequency of 0
+/// removes the key from the map. Empty lines and lines starting with `#` are skipped.
+pub async fn load(
+    path: impl AsRef<Path>,
+) -> Result<DrumMap, Box<dyn std::error::Error + Send + Sync>> {
+    let text = tokio::fs::read_to_string(&path).await?;
+    let mut map = gm_drum_map();
+
+    for (number, line) in text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+    {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = line
+            .split_once(char::is_whitespace)
+            .and_then(|(key, frequency)| {
+                Some((
+                    key.parse::<u8>().ok()?,
+                    frequency.trim().parse::<u16>().ok()?,
+                ))
+            });
+
+        match parsed {
+            Some((key, 0)) => {
+                map.remove(&key);
+            }
+            Some((key, frequency)) => {
+                map.insert(key, frequency);
+            }
+            None => {
+                return Err(format!(
+                    "{}: line {number}: expected `key frequency`",
+                    path.as_ref().to_string_lossy()
+                )
+                .into())
+            }
+        }
+    }
+
+    Ok(map)
+}
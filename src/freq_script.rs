@@ -0,0 +1,83 @@
+use std::{path::Path, time::Duration};
+
+use crate::device::Tone;
+
+/// A sequence of tones given as a text file of `time_ms frequency_hz velocity` lines, played as
+/// is without going through MIDI. A velocity of 0 stops the tone of that frequency. Empty lines
+/// and lines starting with `#` are skipped.
+#[derive(Debug, Clone)]
+pub struct FreqScript {
+    // (time from the start of playback, tone), sorted by time
+    pub steps: Vec<(Duration, Tone)>,
+}
+
+impl FreqScript {
+    pub async fn load(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let text = tokio::fs::read_to_string(&path).await?;
+
+        Self::parse(&text).map_err(|e| format!("{}: {e}", path.as_ref().to_string_lossy()).into())
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut steps: Vec<(Duration, Tone)> = Vec::new();
+
+        for (number, line) in text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+        {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let (Some(time), Some(frequency), Some(vel), None) =
+                (words.next(), words.next(), words.next(), words.next())
+            else {
+                return Err(format!(
+                    "line {number}: expected `time_ms frequency_hz velocity`"
+                ));
+            };
+
+            let time = time
+                .parse::<f64>()
+                .ok()
+                .and_then(|time| Duration::try_from_secs_f64(time / 1000.0).ok())
+                .ok_or_else(|| format!("line {number}: invalid time `{time}`"))?;
+            let frequency = frequency
+                .parse::<u16>()
+                .ok()
+                .filter(|frequency| *frequency > 0)
+                .ok_or_else(|| format!("line {number}: invalid frequency `{frequency}`"))?;
+            let vel = vel
+                .parse::<u8>()
+                .ok()
+                .filter(|vel| *vel <= 127)
+                .ok_or_else(|| format!("line {number}: invalid velocity `{vel}`"))?;
+
+            if steps.last().is_some_and(|(last, _)| *last > time) {
+                return Err(format!("line {number}: times must not decrease"));
+            }
+
+            steps.push((
+                time,
+                Tone {
+                    frequency,
+                    vel,
+                    duty: None,
+                    voice: None,
+                    release_vel: None,
+                    centihertz: None,
+                },
+            ));
+        }
+
+        if steps.is_empty() {
+            return Err("no tones".into());
+        }
+
+        Ok(Self { steps })
+    }
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+use log::info;
+
+use crate::{
+    args::PlayOptions,
+    midi::{key_name, EventKind, MidiSequence, OctaveNaming, TempoMap, Track},
+    play::{played_centihertz, Tuning},
+};
+
+fn format_name(format: midly::Format) -> &'static str {
+    match format {
+        midly::Format::SingleTrack => "0 (single track)",
+        midly::Format::Parallel => "1 (simultaneous tracks)",
+        midly::Format::Sequential => "2 (independent tracks)",
+    }
+}
+
+// the tick of the last event of the selected tracks
+fn last_tick(sequence: &MidiSequence) -> u64 {
+    sequence
+        .tracks
+        .iter()
+        .filter_map(|track| track.clone().with_ticks().last().map(|(tick, _)| tick))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Prints a single line with the path, format, track count, note count and duration in seconds
+/// of the file, separated by tabs for scripts going over many files
+pub fn print_counts(path: &Path, sequence: &MidiSequence, tempo_map: &TempoMap) {
+    let format = match sequence.header.format {
+        midly::Format::SingleTrack => 0,
+        midly::Format::Parallel => 1,
+        midly::Format::Sequential => 2,
+    };
+    let notes = sequence
+        .tracks
+        .iter()
+        .flat_map(|track| track.clone())
+        .filter(|event| matches!(event.kind, Some(EventKind::NoteUpdate { vel, .. }) if vel != 0))
+        .count();
+
+    println!(
+        "{}\t{format}\t{}\t{notes}\t{:.3}",
+        path.to_string_lossy(),
+        sequence.tracks.len(),
+        tempo_map.time_at(last_tick(sequence)).as_secs_f64()
+    );
+}
+
+/// Prints the header of the file and what its selected tracks contain
+pub fn print(sequence: &MidiSequence, tempo_map: &TempoMap) {
+    println!("format: {}", format_name(sequence.header.format));
+    println!("tracks: {}", sequence.tracks.len());
+
+    match sequence.header.timing {
+        midly::Timing::Metrical(ticks_per_beat) => {
+            println!("timing: metrical, {ticks_per_beat} ticks per beat")
+        }
+        midly::Timing::Timecode(fps, subframe) => println!(
+            "timing: timecode, {} fps with {subframe} ticks per frame",
+            fps.as_f32()
+        ),
+    }
+
+    let us_per_beat = tempo_map.us_per_tick_at(0) * sequence.timing.ticks_per_beat as f64;
+    println!(
+        "initial tempo: {:.2} bpm ({} µs per beat)",
+        60_000_000.0 / us_per_beat,
+        us_per_beat.round()
+    );
+
+    if let Some(offset) = sequence.smpte_offset() {
+        println!("smpte offset: {:.3} s", offset.as_secs_f64());
+    }
+
+    println!(
+        "duration: {:.3} s",
+        tempo_map.time_at(last_tick(sequence)).as_secs_f64()
+    );
+
+    for track in sequence.tracks.iter() {
+        let name = track.name().unwrap_or_else(|| "Unknown".into());
+        let instrument = track.instrument().unwrap_or_else(|| "Unknown".into());
+
+        let mut events = 0;
+        let mut notes = 0;
+        let mut end_tick = 0;
+        for (tick, event) in track.clone().with_ticks() {
+            events += 1;
+            end_tick = tick;
+
+            if let Some(EventKind::NoteUpdate { vel, .. }) = event.kind {
+                if vel != 0 {
+                    notes += 1;
+                }
+            }
+        }
+
+        println!(
+            "{:<2} - name: {name:<32} - instrument: {instrument:<24} - events: {events:<6} - notes: {notes:<6} - length: {:.3} s",
+            track.index,
+            tempo_map.time_at(end_tick).as_secs_f64()
+        );
+    }
+}
+
+// the name of the variant of a raw event, like `PitchBend` or `meta TimeSignature`
+fn event_type(kind: &midly::TrackEventKind) -> String {
+    let variant = |debug: String| {
+        debug
+            .split([' ', '(', '{'])
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    match kind {
+        midly::TrackEventKind::Midi { message, .. } => variant(format!("{message:?}")),
+        midly::TrackEventKind::Meta(message) => format!("meta {}", variant(format!("{message:?}"))),
+        midly::TrackEventKind::SysEx(_) => "SysEx".into(),
+        midly::TrackEventKind::Escape(_) => "Escape".into(),
+    }
+}
+
+/// Logs how many events of every type the tracks contain that playback ignores, to tell at a
+/// glance whether the file depends on something the player doesn't do
+pub fn report_unsupported(tracks: &[Track]) {
+    let mut counts = BTreeMap::<String, usize>::new();
+
+    for track in tracks {
+        for (raw, event) in track.raw_events() {
+            // marks the end of every track, nothing is missing for it
+            if event.kind.is_some()
+                || matches!(
+                    raw.kind,
+                    midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack)
+                )
+            {
+                continue;
+            }
+
+            *counts.entry(event_type(&raw.kind)).or_default() += 1;
+        }
+    }
+
+    if counts.is_empty() {
+        info!("no unsupported events");
+        return;
+    }
+
+    info!("unsupported events, ignored during playback:");
+    for (event_type, count) in counts {
+        info!("{event_type:<24} {count}");
+    }
+}
+
+/// Prints every event of the track with its tick and delta, as the MIDI message it was parsed
+/// from and the event it converts to
+pub fn dump_events(track: &Track, octave_naming: OctaveNaming) {
+    println!("track {}:", track.index);
+    println!("{:>10} {:>8}  event", "tick", "delta");
+
+    let mut tick = 0u64;
+    for (raw, event) in track.raw_events() {
+        tick += event.delta as u64;
+
+        match event.kind {
+            Some(kind @ EventKind::NoteUpdate { key, .. }) => println!(
+                "{tick:>10} {:>8}  {kind:?} {}",
+                event.delta,
+                key_name(key, octave_naming)
+            ),
+            Some(kind) => println!("{tick:>10} {:>8}  {kind:?}", event.delta),
+            None => println!("{tick:>10} {:>8}  ignored {:?}", event.delta, raw.kind),
+        }
+    }
+}
+
+// a JSON string literal of the text
+fn json_string(text: &str) -> String {
+    let mut json = String::from('"');
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// the fields of an event past its track and time, as JSON members
+fn json_fields(kind: &EventKind, frequency: Option<u32>) -> String {
+    match kind {
+        EventKind::NoteUpdate {
+            channel, key, vel, ..
+        } => {
+            let frequency = match frequency {
+                Some(centihertz) => format!("{:.2}", centihertz as f64 / 100.0),
+                None => "null".into(),
+            };
+            format!(
+                r#""kind": "note", "channel": {channel}, "key": {key}, "vel": {vel}, "frequency": {frequency}"#
+            )
+        }
+        EventKind::Aftertouch { key, value } => format!(
+            r#""kind": "aftertouch", "key": {}, "value": {value}"#,
+            key.map_or("null".into(), |key| key.to_string())
+        ),
+        EventKind::Controller { controller, value } => {
+            format!(r#""kind": "controller", "controller": {controller}, "value": {value}"#)
+        }
+        EventKind::TempoUpdate(us_per_beat) => {
+            format!(r#""kind": "tempo", "us_per_beat": {us_per_beat}"#)
+        }
+        EventKind::TrackName(name) => format!(r#""kind": "name", "text": {}"#, json_string(name)),
+        EventKind::TrackInstrument(instrument) => format!(
+            r#""kind": "instrument", "text": {}"#,
+            json_string(instrument)
+        ),
+        EventKind::Directive(directive) => format!(
+            r#""kind": "directive", "text": {}"#,
+            json_string(&format!("{directive:?}"))
+        ),
+        EventKind::SmpteOffset(offset) => format!(
+            r#""kind": "smpte_offset", "seconds": {:.6}"#,
+            offset.as_secs_f64()
+        ),
+        EventKind::Tuning(changes) => format!(
+            r#""kind": "tuning", "keys": [{}]"#,
+            changes
+                .iter()
+                .map(|(key, pitch)| format!(r#"{{"key": {key}, "pitch": {pitch:.4}}}"#))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Writes the events of the tracks as a JSON array, in the order they are played with their
+/// time in ms and the frequencies notes are played at. Events left out with --enable-events are
+/// left out here as well
+pub async fn export_json(
+    sequence: &MidiSequence,
+    tempo_map: &TempoMap,
+    options: &PlayOptions,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut events = sequence
+        .tracks
+        .iter()
+        .flat_map(|track| {
+            track
+                .clone()
+                .with_ticks()
+                .filter_map(move |(tick, event)| Some((tick, track, event.kind?)))
+        })
+        .filter(|(_, _, kind)| options.is_enabled(kind))
+        .collect::<Vec<_>>();
+    // stable, so the events of a track keep their order
+    events.sort_by_key(|(tick, track, _)| (*tick, track.index));
+
+    // a tuning applies to every track, like during playback
+    let mut tuning = Tuning::new();
+    // (track, key) -> the frequency a sounding note was started at
+    let mut sounding = HashMap::new();
+
+    let mut lines = Vec::new();
+    for (tick, track, kind) in events {
+        let frequency = match &kind {
+            EventKind::Tuning(changes) => {
+                tuning.extend(changes.iter().copied());
+                None
+            }
+            EventKind::NoteUpdate {
+                channel,
+                key,
+                vel: 0,
+                ..
+            } => sounding
+                .remove(&(track.index, *key))
+                .flatten()
+                .or_else(|| played_centihertz(*key, *channel, 0, &tuning, options)),
+            EventKind::NoteUpdate { channel, key, .. } => {
+                let frequency = played_centihertz(*key, *channel, 0, &tuning, options);
+                sounding.insert((track.index, *key), frequency);
+                frequency
+            }
+            _ => None,
+        };
+
+        lines.push(format!(
+            r#"  {{"track": {}, "tick": {tick}, "time_ms": {:.3}, {}}}"#,
+            track.index,
+            tempo_map.time_at(tick).as_secs_f64() * 1000.0,
+            json_fields(&kind, frequency)
+        ));
+    }
+
+    let json = format!("[\n{}\n]\n", lines.join(",\n"));
+    tokio::fs::write(path, json)
+        .await
+        .map_err(|e| format!("{}: {e}", path.to_string_lossy()))?;
+
+    info!(
+        "exported {} event(s) to {}",
+        lines.len(),
+        path.to_string_lossy()
+    );
+
+    Ok(())
+}
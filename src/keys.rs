@@ -0,0 +1,161 @@
+use std::io::IsTerminal;
+
+use log::debug;
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Escape,
+}
+
+/// Reads keypresses from the terminal as they happen, without waiting for a newline and without
+/// echoing them. The terminal is put back the way it was once the reader is dropped.
+pub struct KeyReader {
+    rx: mpsc::Receiver<Key>,
+    _terminal: TerminalMode,
+}
+
+impl KeyReader {
+    /// Returns None if stdin isn't a terminal, or raw keypresses aren't supported on this platform
+    pub fn spawn() -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        if !std::io::stdin().is_terminal() {
+            debug!("stdin is not a terminal, not reading keys");
+            return Ok(None);
+        }
+
+        let Some(terminal) = TerminalMode::raw()? else {
+            return Ok(None);
+        };
+
+        let (tx, rx) = mpsc::channel(16);
+
+        // the blocking pool also exists on the current thread runtime of the single-thread
+        // feature. The reader polls stdin with a timeout so that it notices when the receiver
+        // is gone, otherwise it would block the shutdown of the runtime waiting for a keypress
+        tokio::task::spawn_blocking(move || read_keys(tx));
+
+        Ok(Some(Self {
+            rx,
+            _terminal: terminal,
+        }))
+    }
+
+    pub async fn next(&mut self) -> Option<Key> {
+        self.rx.recv().await
+    }
+}
+
+// splits the bytes of one read into keys, escape sequences of the arrow keys arrive in one piece
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let text = String::from_utf8_lossy(bytes);
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' if chars.peek() == Some(&'[') => {
+                chars.next();
+                match chars.next() {
+                    Some('A') => Key::Up,
+                    Some('B') => Key::Down,
+                    Some('C') => Key::Right,
+                    Some('D') => Key::Left,
+                    _ => continue,
+                }
+            }
+            '\x1b' => Key::Escape,
+            c => Key::Char(c),
+        };
+
+        keys.push(key);
+    }
+
+    keys
+}
+
+#[cfg(target_family = "unix")]
+fn read_keys(tx: mpsc::Sender<Key>) {
+    const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+    let mut buf = [0u8; 32];
+
+    while !tx.is_closed() {
+        let mut fd = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        // SAFETY: fd is a valid pollfd for the duration of the call
+        let ready = unsafe { libc::poll(&mut fd, 1, POLL_TIMEOUT_MS) };
+        if ready <= 0 {
+            continue;
+        }
+
+        // SAFETY: buf is valid for writes of its length
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+        if n <= 0 {
+            break;
+        }
+
+        for key in parse_keys(&buf[..n as usize]) {
+            if tx.blocking_send(key).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn read_keys(_: mpsc::Sender<Key>) {}
+
+/// Disables line buffering and echo of the terminal until dropped
+#[cfg(target_family = "unix")]
+struct TerminalMode(libc::termios);
+
+#[cfg(target_family = "unix")]
+impl TerminalMode {
+    fn raw() -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        // SAFETY: termios is plain data that tcgetattr fills in
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        // signals stay enabled, so Ctrl-C still stops playback gracefully
+        let mut raw = original;
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(Some(Self(original)))
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read by tcgetattr
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+struct TerminalMode;
+
+#[cfg(not(target_family = "unix"))]
+impl TerminalMode {
+    fn raw() -> Result<Option<Self>, Box<dyn std::error::Error + Send + Sync>> {
+        log::warn!("keypresses can only be read on unix, hotkeys are disabled");
+        Ok(None)
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use log::info;
+use tokio::time::Instant;
+
+use crate::{
+    device::BoxedDevice,
+    keys::{Key, KeyReader},
+    state,
+};
+
+const LATENCY_STATE: &str = "latency_ms";
+
+// how many tones are tapped along to, the median of the delays is taken
+const ROUNDS: u32 = 5;
+const FREQUENCY: u16 = 440;
+
+/// Sounds a tone a few times and measures how long it takes until a key is pressed in response.
+/// The result includes the reaction time of whoever presses the key, so it is an upper bound of
+/// the latency of the serial link and the firmware. It is remembered for --latency-offset.
+pub async fn measure(
+    device: &mut BoxedDevice,
+) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    let mut keys = KeyReader::spawn()?.ok_or("measuring the latency needs a terminal")?;
+
+    info!("press any key as soon as you hear the tone, q or Esc to cancel");
+
+    let mut delays = Vec::new();
+    for round in 0..ROUNDS {
+        // irregular pauses, so that the tone can't be anticipated. Keys pressed during them are
+        // too early to count
+        let pause = tokio::time::sleep(Duration::from_millis(1000 + 370 * (round as u64 * 7 % 5)));
+        tokio::pin!(pause);
+        loop {
+            tokio::select! {
+                _ = &mut pause => break,
+                key = keys.next() => match key {
+                    Some(Key::Char('q') | Key::Escape) | None => {
+                        return Err("latency measurement cancelled".into())
+                    }
+                    Some(_) => info!("too early, wait for the tone"),
+                },
+            }
+        }
+
+        let sent = Instant::now();
+        device.tone_update(FREQUENCY, 127).await?;
+
+        let key = keys.next().await;
+        let delay = sent.elapsed();
+        device.note_off(FREQUENCY).await?;
+
+        match key {
+            Some(Key::Char('q') | Key::Escape) | None => {
+                device.reset().await?;
+                return Err("latency measurement cancelled".into());
+            }
+            Some(_) => {
+                info!("{}/{ROUNDS}: {} ms", round + 1, delay.as_millis());
+                delays.push(delay);
+            }
+        }
+    }
+
+    delays.sort();
+    let latency = delays[delays.len() / 2];
+
+    state::save(LATENCY_STATE, &latency.as_millis().to_string());
+    info!(
+        "latency: {} ms, remembered for --latency-offset",
+        latency.as_millis()
+    );
+
+    Ok(latency)
+}
+
+/// The latency remembered by the last measurement
+pub fn remembered() -> Option<Duration> {
+    state::load(LATENCY_STATE)?
+        .parse()
+        .ok()
+        .map(Duration::from_millis)
+}
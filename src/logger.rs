@@ -0,0 +1,36 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+/// Writes log records to stderr, prefixing everything that is not plain information with its
+/// level. stderr is unbuffered, so records show up right away even when it is piped.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match record.level() {
+            Level::Info => eprintln!("{}", record.args()),
+            Level::Warn => eprintln!("warning: {}", record.args()),
+            Level::Error => eprintln!("error: {}", record.args()),
+            Level::Debug => eprintln!("debug: {}", record.args()),
+            Level::Trace => eprintln!("trace: {}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+pub fn init(level: LevelFilter) {
+    // only fails if a logger is already set, in which case that one is used
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
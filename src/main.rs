@@ -1,35 +1,74 @@
-use crate::device::Device;
-use midi::MidiSequence;
-use play::{play_track, InstrumentCount};
-use std::{
-    process::exit,
-    sync::{Arc, Weak},
-};
-use tokio::sync::{broadcast, Barrier, Mutex};
+use device::Tone;
+use futures::StreamExt;
+use log::{debug, info, warn};
+use midi::{MidiSequence, TempoMap};
+use play::{play_sequence, InstrumentCount, PlayContext, RepeatSection, Seek};
+use std::{io::Write, path::Path, process::exit, sync::Arc, time::Duration};
+use tempo_file::TempoFile;
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::Instant;
 
 mod args;
 mod device;
+mod drums;
+mod freq_script;
+mod info;
+mod keys;
+mod latency;
+mod logger;
 mod midi;
+mod midi_in;
+mod midi_out;
+mod osc;
+mod piano_roll;
 mod play;
+#[cfg(all(feature = "pwm", target_os = "linux"))]
+mod pwm;
+mod state;
+mod tempo_file;
+mod voices;
+
+#[cfg(all(feature = "pwm", not(target_os = "linux")))]
+compile_error!("the pwm feature is only available on linux");
 
 #[cfg(all(feature = "single-thread", feature = "multi-thread"))]
 compile_error!("single-thread and multi-thread are mutually exclusive features");
 
-#[cfg(feature = "multi-thread")]
-type DeviceMutex = Mutex<dyn Device + Send + Sync>;
-#[cfg(feature = "single-thread")]
-type DeviceMutex = Mutex<dyn Device + Send>;
+/* runtime flavors
+single-thread runs everything on one current thread runtime, multi-thread on a worker pool.
+every feature works with both:
+
+* the port selection prompt blocks in `read_input`, which stalls the single runtime thread, but
+  it runs before any other task has been spawned
+* the hotkey reader blocks in `spawn_blocking`, which gets its own thread from the blocking pool
+  on both flavors. It polls stdin with a timeout, so it doesn't hold up the shutdown of either.
+* the --scrub position reader blocks reading lines from stdin in `spawn_blocking` without a
+  timeout, the shutdown only waits a moment for it like for the MIDI input
+* `block_in_place` panics on a current thread runtime and must not be used, anything blocking
+  has to go through `spawn_blocking` instead
+ */
 
 /* message format sent to device
 big endian transmission format
 first byte: message type
 0x01 : tone update
+0x02 : reset
+0x03 : get id
+0x04 : duty update
+0x05 : voice update
+0x06 : note off
+0x07 : release
+0x08 : centihertz update
+0x09 : get features
 
 tone update message layout
 01 xx xx yy 01
 
 x: u16 tone
-y: u16 velocity
+y: u8 velocity
+
+the layouts of the others are built in device.rs and described in full in the PROTOCOL comment
+of synthesizer/synthesizer.ino, which is the reference for all of them
  */
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -42,64 +81,802 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .enable_all()
         .build()?;
 
-    rt.block_on(async_main()).unwrap();
+    let result = rt.block_on(async_main());
+
+    // drops the tasks that are still running, so that they clean up before the process exits.
+    // A read of the MIDI input can block forever waiting for the next message, so blocking
+    // tasks are only waited on for a moment
+    rt.shutdown_timeout(Duration::from_millis(200));
+
+    if let Err(e) = result {
+        log::error!("{e}");
+        exit(1);
+    }
 
     Ok(())
 }
 
 async fn async_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let args = args::Args::parse();
+    let mut args = args::Args::parse();
+
+    logger::init(args.log_level);
+
+    if let Some(run) = args.show_config {
+        writeln!(std::io::stdout().lock(), "{args:#?}")?;
+        if !run {
+            return Ok(());
+        }
+    }
 
-    let midi_sequence = MidiSequence::parse_file(
-        &args.file_path,
-        args.tracks.map(|x| x.into_iter()),
-        args.initial_tick,
+    if args.list_ports {
+        device::list_ports()?;
+        return Ok(());
+    }
+
+    if args.reset {
+        let mut device = open_device(&args).await?;
+        device.reset().await?;
+        info!("device reset");
+        return Ok(());
+    }
+
+    if args.test {
+        let mut device = open_device(&args).await?;
+        play::play_test_scale(&mut device, &args.play_options).await?;
+        return Ok(());
+    }
+
+    if args.measure_latency {
+        let mut device = open_device(&args).await?;
+        latency::measure(&mut device).await?;
+        return Ok(());
+    }
+
+    if let Some(offset) = args.latency_offset {
+        args.play_options.latency_offset = offset
+            .or_else(latency::remembered)
+            .ok_or("no latency remembered, run --measure-latency first")?;
+        info!(
+            "playing {} ms early",
+            args.play_options.latency_offset.as_millis()
+        );
+    }
+
+    if let Some(path) = &args.drum_map_file {
+        args.play_options.drum_map = Some(drums::load(path).await?);
+    }
+
+    if let Some(path) = &args.midi_in {
+        return play_live(&args, path).await;
+    }
+
+    if let Some(path) = &args.freq_script {
+        return play_freq_script(&args, path).await;
+    }
+
+    let file_path = args.file_path.as_ref().ok_or("no input file")?;
+
+    let mut midi_sequence = MidiSequence::parse_file(
+        file_path,
+        args.tracks.as_ref().map(|x| x.iter().copied()),
+        args.timing,
         args.list,
+        args.strict,
+        args.lenient,
     )
     .await?;
+    if args.list {
+        return Ok(());
+    }
+    if let Some(index) = args.split_track_channels {
+        for split in midi_sequence.split_track_channels(index)? {
+            info!("split off track {split} from track {index}");
+        }
+    }
+    midi_sequence.set_key_offset(args.key_offset);
+    if args.align_first_note {
+        midi_sequence.align_first_notes();
+    }
 
-    let device = device::new(args.baud_rate, args.dry_run, args.ignore_id).await?;
+    if args.report_unsupported {
+        info::report_unsupported(&midi_sequence.tracks);
+    }
 
-    let instrument_count = Arc::new(Mutex::new(InstrumentCount { current: 0, max: 0 }));
+    if let Some(index) = args.dump_events {
+        let track = midi_sequence
+            .tracks
+            .iter()
+            .find(|track| track.index == index)
+            .ok_or_else(|| format!("track {index} does not exist or is not selected"))?;
 
-    let barrier = Arc::new(Barrier::new(midi_sequence.tracks.len()));
-    let (sender, _) = broadcast::channel(8);
+        info::dump_events(track, args.play_options.octave_naming);
+        return Ok(());
+    }
 
-    tokio::spawn(handle_ctrlc(Arc::downgrade(&device)));
+    let tempo_schedule = match &args.tempo_file {
+        Some((path, interpolation)) => Some(TempoFile::load(path).await?.schedule(
+            *interpolation,
+            midi_sequence.timing.ticks_per_beat,
+            args.play_options.speed.tempo,
+        )),
+        None => None,
+    };
 
-    let f = futures::future::join_all(midi_sequence.tracks.into_iter().map(|track| {
-        tokio::task::spawn(play_track(
-            track,
-            midi_sequence.timing,
-            device.clone(),
+    let tempo_map = tempo_map(&args, &mut midi_sequence, tempo_schedule.as_deref());
+
+    if args.info {
+        info::print(&midi_sequence, &tempo_map);
+        return Ok(());
+    }
+
+    if args.count_only {
+        info::print_counts(file_path, &midi_sequence, &tempo_map);
+        return Ok(());
+    }
+
+    if let Some(path) = &args.export_json {
+        return info::export_json(&midi_sequence, &tempo_map, &args.play_options, path).await;
+    }
+
+    if let Some(resolution) = args.piano_roll {
+        let audible_tracks = midi_sequence
+            .tracks
+            .iter()
+            .filter(|track| args.play_options.is_audible(track.index))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        piano_roll::print(
+            &audible_tracks,
+            &tempo_map,
+            resolution,
+            args.play_options.octave_naming,
+        );
+        return Ok(());
+    }
+
+    if args.print_events {
+        return print_events(&args, midi_sequence, tempo_schedule).await;
+    }
+
+    let layers = parse_layers(&args, midi_sequence.track_count).await?;
+
+    if let Some((max_voices, policy)) = args.max_voices {
+        // the voices are shared by the layers
+        let audible_tracks = std::iter::once((&midi_sequence, &tempo_map))
+            .chain(layers.iter().map(|(layer, tempo_map)| (layer, tempo_map)))
+            .map(|(sequence, tempo_map)| {
+                let tracks = sequence
+                    .tracks
+                    .iter()
+                    .filter(|track| args.play_options.is_audible(track.index))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (tracks, tempo_map)
+            })
+            .collect::<Vec<_>>();
+        let sequences = audible_tracks
+            .iter()
+            .map(|(tracks, tempo_map)| (tracks.as_slice(), *tempo_map))
+            .collect::<Vec<_>>();
+
+        args.play_options.culled_notes = voices::cull(&sequences, max_voices, policy);
+    }
+
+    match (midi_sequence.smpte_offset(), args.honor_smpte_offset) {
+        (Some(offset), true) => {
+            info!(
+                "smpte offset: {:.3} s, waiting for it before starting",
+                offset.as_secs_f64()
+            );
+            args.play_options.smpte_offset = offset;
+        }
+        (Some(offset), false) => info!("smpte offset: {:.3} s", offset.as_secs_f64()),
+        (None, _) => (),
+    }
+
+    let repeat = args.repeat.map(|(start, end)| {
+        let start_tick = tempo_map.tick_at(start);
+        let end_tick = tempo_map.tick_at(end);
+        // the section would be jumped back into without anything being played
+        if start_tick >= end_tick {
+            return Err(format!(
+                "the section from {start:?} to {end:?} to repeat doesn't span a tick"
+            ));
+        }
+        let transpose = args.transpose_each_loop;
+
+        let mut count = args.repeat_count;
+        if transpose != 0 {
+            let (lowest, highest) = midi_sequence
+                .tracks
+                .iter()
+                .filter(|track| args.play_options.is_audible(track.index))
+                .filter_map(|track| track.key_range())
+                .fold((127, 0), |(lowest, highest), (low, high)| {
+                    (lowest.min(low), highest.max(high))
+                });
+
+            // how many times the keys can be transposed before leaving 0 to 127
+            let max_count = if transpose > 0 {
+                127u8.saturating_sub(highest) as u32 / transpose as u32
+            } else {
+                lowest as u32 / transpose.unsigned_abs() as u32
+            };
+
+            if count.is_none_or(|count| count > max_count) {
+                info!(
+                    "repeating {max_count} time(s), transposing further would leave the key range"
+                );
+                count = Some(max_count);
+            }
+        }
+
+        Ok(RepeatSection {
+            start_tick,
+            end_tick,
+            start_tick_us: tempo_map.us_per_tick_at(start_tick).round() as u32,
+            count,
+            transpose,
+        })
+    });
+    let repeat = repeat.transpose()?;
+
+    let device = open_device(&args).await?;
+    let (device, writer) =
+        device::spawn_writer(device, args.zero_velocity, args.play_options.soft_reset);
+    let osc = open_osc(&args).await?;
+
+    let instrument_count = Arc::new(Mutex::new(InstrumentCount::default()));
+    let stuck_note_watchdog = args.stuck_note_timeout.map(|timeout| {
+        tokio::spawn(watch_stuck_notes(
             instrument_count.clone(),
-            args.speed,
-            barrier.clone(),
-            sender.clone(),
+            device.clone(),
+            timeout,
         ))
-    }));
+    });
+
+    let play_options = Arc::new(args.play_options);
 
-    for i in f.await {
-        match i? {
-            Ok(_) => (),
-            Err(e) => return Err(e),
+    let (stop_tx, mut stop_rx) = watch::channel(false);
+
+    // the positions of --scrub come in over stdin instead of keys
+    let (keys, seek) = if args.scrub {
+        let (seek_tx, seek_rx) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || read_positions(tempo_map, seek_tx));
+        info!("reading the positions to play from from stdin");
+        (None, Some(seek_rx))
+    } else {
+        (keys::KeyReader::spawn()?, None)
+    };
+    if keys.is_some() {
+        info!("press q or Esc to stop playback");
+    }
+
+    tokio::spawn(handle_stop(stop_tx, keys));
+
+    let track_names = std::iter::once(&midi_sequence)
+        .chain(layers.iter().map(|(layer, _)| layer))
+        .flat_map(|sequence| &sequence.tracks)
+        .map(|track| {
+            (
+                track.index,
+                track.name().unwrap_or_else(|| "Unknown".into()),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // softer than full velocity, so that the notes of the file can still be heard over it
+    const REFERENCE_VELOCITY: u8 = 64;
+    if let Some(frequency) = args.reference_tone {
+        info!("sounding a reference tone of {frequency} Hz");
+        let tone = Tone {
+            frequency,
+            vel: REFERENCE_VELOCITY,
+            duty: None,
+            voice: None,
+            release_vel: None,
+            centihertz: None,
+        };
+        if let Err(e) = device.tone_update(Instant::now(), tone).await {
+            if let Some(watchdog) = stuck_note_watchdog {
+                watchdog.abort();
+                let _ = watchdog.await;
+            }
+            return Err(reset_after_failure(device, writer, e).await);
         }
     }
 
+    // the layers are played along from the same start
+    let start = play::playback_start(play_options.smpte_offset, play_options.latency_offset);
+    let context = |timing, repeat, tempo_schedule, seek| PlayContext {
+        timing,
+        device: device.clone(),
+        instrument_count: instrument_count.clone(),
+        options: play_options.clone(),
+        repeat,
+        tempo_schedule,
+        osc: osc.clone(),
+        start,
+        stop: stop_rx.clone(),
+        seek,
+        events: None,
+    };
+
+    let mut layer_tasks = layers
+        .into_iter()
+        .map(|(layer, _)| {
+            tokio::spawn(play_sequence(
+                layer.tracks,
+                context(layer.timing, None, None, None),
+            ))
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let played = async {
+        let mut track_counts = play_sequence(
+            midi_sequence.tracks,
+            context(midi_sequence.timing, repeat, tempo_schedule, seek),
+        )
+        .await?;
+        for task in layer_tasks.by_ref() {
+            track_counts.extend(task.await??);
+        }
+
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(track_counts)
+    }
+    .await;
+    let track_counts = match played {
+        Ok(track_counts) => track_counts,
+        Err(e) => {
+            // the layers still playing and the watchdog hold handles to the device as well
+            for task in layer_tasks {
+                task.abort();
+                let _ = task.await;
+            }
+            if let Some(watchdog) = stuck_note_watchdog {
+                watchdog.abort();
+                let _ = watchdog.await;
+            }
+            return Err(reset_after_failure(device, writer, e).await);
+        }
+    };
+
+    info!("peak simultaneous notes per track:");
+    for ((index, name), track_count) in track_names.iter().zip(track_counts) {
+        info!("{index:<2} - name: {name:<32} - peak: {}", track_count.max);
+    }
+    info!(
+        "peak simultaneous notes overall: {}",
+        instrument_count.lock().await.max
+    );
+
+    if play_options.hold_end && !*stop_rx.borrow() {
+        info!("holding the last notes, press Ctrl-C to stop");
+        while !*stop_rx.borrow_and_update() {
+            if stop_rx.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // a soft reset also fades out what is left sounding at the end, like the reference tone
+    let ended = if *stop_rx.borrow() || play_options.soft_reset.is_some() {
+        device.reset(Instant::now()).await
+    } else if let Some(frequency) = args.reference_tone {
+        device
+            .tone_update(Instant::now(), Tone::off(frequency))
+            .await
+    } else {
+        Ok(())
+    };
+
+    // the watchdog holds a handle as well
+    let watched = match stuck_note_watchdog {
+        Some(watchdog) => {
+            watchdog.abort();
+            watchdog.await.unwrap_or(Ok(()))
+        }
+        None => Ok(()),
+    };
+
+    // the writer finishes the queued writes once the last handle is gone. Writes only fail once
+    // the writer has stopped, so its error comes first
+    drop(device);
+    let stats = writer.await??;
+    ended?;
+    watched?;
+    if let Some(stats) = stats {
+        info!(
+            "wrote {} messages, {} bytes, {:.1} messages/s, {:.1} bytes/s",
+            stats.messages,
+            stats.bytes,
+            stats.messages_per_second(),
+            stats.bytes_per_second()
+        );
+        info!(
+            "serial link utilization at {} baud: {:.1}% on average, {:.1}% at peak",
+            stats.baud_rate,
+            stats.utilization() * 100.0,
+            stats.peak_utilization() * 100.0
+        );
+    }
+
     Ok(())
 }
 
-async fn handle_ctrlc(
-    device: Weak<DeviceMutex>,
+// prints the events of the tracks that are heard as playback reaches them, timed the same way
+async fn print_events(
+    args: &args::Args,
+    midi_sequence: MidiSequence,
+    tempo_schedule: Option<Vec<(Duration, u32)>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    tokio::signal::ctrl_c().await?;
+    // nothing is sounded, the device only stands in for the one of playback
+    let (device, _) = device::spawn_writer(
+        Box::new(device::DummyDevice::default()),
+        args.zero_velocity,
+        None,
+    );
+    let (_stop_tx, stop_rx) = watch::channel(false);
+
+    let start = play::playback_start(
+        args.play_options.smpte_offset,
+        args.play_options.latency_offset,
+    );
+    let context = PlayContext {
+        timing: midi_sequence.timing,
+        device,
+        instrument_count: Arc::new(Mutex::new(InstrumentCount::default())),
+        options: Arc::new(args.play_options.clone()),
+        repeat: None,
+        tempo_schedule,
+        osc: None,
+        start,
+        stop: stop_rx,
+        seek: None,
+        events: None,
+    };
+    let events = play::event_stream(midi_sequence.tracks, context);
+    futures::pin_mut!(events);
+
+    // stdout is line buffered even when it is piped, so each event reaches the reading
+    // process as it happens. Once that process is gone there is nobody left to print for
+    while let Some(event) = events.next().await {
+        let (index, kind) = event?;
+        let line = format!(
+            "{:>9.3} s  track {index:<2} {kind:?}\n",
+            start.elapsed().as_secs_f64()
+        );
+
+        match std::io::stdout().write_all(line.as_bytes()) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+            result => result?,
+        }
+    }
+
+    Ok(())
+}
+
+// the tempo map of a sequence, setting its initial tick to the one of the tempo file or the
+// fixed tempo
+fn tempo_map(
+    args: &args::Args,
+    midi_sequence: &mut MidiSequence,
+    tempo_schedule: Option<&[(Duration, u32)]>,
+) -> TempoMap {
+    if let Some(schedule) = tempo_schedule {
+        midi_sequence.timing.tick = Duration::from_micros(schedule[0].1.into());
+        info!(
+            "following the tempo file, initial tick: {} µs",
+            midi_sequence.timing.tick.as_micros()
+        );
+
+        TempoMap::from_schedule(schedule)
+    } else if args.play_options.fixed_tempo {
+        let tick = match args.bpm {
+            Some(bpm) => {
+                Duration::from_secs_f64(60.0 / bpm / midi_sequence.timing.ticks_per_beat as f64)
+            }
+            None => midi_sequence.timing.tick,
+        };
 
-    if let Some(arc) = device.upgrade() {
-        let mut device_lock = arc.lock().await;
-        device_lock.reset().await?;
+        // no tempo update is ever played to apply the tempo shift, so it goes into the tick
+        midi_sequence.timing.tick = tick.div_f64(args.play_options.speed.tempo);
+        info!(
+            "fixed tempo, tick: {} µs",
+            midi_sequence.timing.tick.as_micros()
+        );
 
-        exit(0);
+        TempoMap::new(&[], midi_sequence.timing, 1.0)
+    } else {
+        TempoMap::new(
+            &midi_sequence.tracks,
+            midi_sequence.timing,
+            args.play_options.speed.tempo,
+        )
     }
+}
+
+// parses the files of --layer, numbering their tracks on from the last track of the file before
+async fn parse_layers(
+    args: &args::Args,
+    mut first_index: usize,
+) -> Result<Vec<(MidiSequence, TempoMap)>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut layers = Vec::new();
+
+    for path in &args.layers {
+        let mut midi_sequence = MidiSequence::parse_file(
+            path,
+            None::<std::iter::Empty<usize>>,
+            // the tempo track is one of the main file, the layers follow their own tempo
+            midi::TimingOverrides {
+                tempo_track: None,
+                ..args.timing
+            },
+            false,
+            args.strict,
+            args.lenient,
+        )
+        .await?;
+        midi_sequence.set_key_offset(args.key_offset);
+        if args.align_first_note {
+            midi_sequence.align_first_notes();
+        }
+        midi_sequence.offset_track_indices(first_index);
+        if args.report_unsupported {
+            info::report_unsupported(&midi_sequence.tracks);
+        }
+        info!(
+            "layer {}: playing its tracks as tracks {first_index} to {}",
+            path.to_string_lossy(),
+            first_index + midi_sequence.track_count - 1
+        );
+        first_index += midi_sequence.track_count;
+
+        let tempo_map = tempo_map(args, &mut midi_sequence, None);
+        layers.push((midi_sequence, tempo_map));
+    }
+
+    Ok(layers)
+}
+
+// plays the notes of a MIDI input port until it is closed or playback is stopped
+async fn play_live(
+    args: &args::Args,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let input = midi_in::MidiInput::open(path).await?;
+
+    let device = open_device(args).await?;
+    let (device, writer) =
+        device::spawn_writer(device, args.zero_velocity, args.play_options.soft_reset);
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    tokio::spawn(handle_stop(stop_tx, keys::KeyReader::spawn()?));
+
+    info!(
+        "playing from {}, press Ctrl-C to stop",
+        path.to_string_lossy()
+    );
+
+    let osc = open_osc(args).await?;
+    let count = match play::play_live(
+        input,
+        device.clone(),
+        osc,
+        Arc::new(args.play_options.clone()),
+        stop_rx,
+    )
+    .await
+    {
+        Ok(count) => count,
+        Err(e) => {
+            return Err(reset_after_failure(device, writer, e).await);
+        }
+    };
+
+    info!("peak simultaneous notes: {}", count.max);
+
+    let reset = device.reset(Instant::now()).await;
+
+    // writes only fail once the writer has stopped, so its error comes first
+    drop(device);
+    writer.await??;
+    reset?;
+
+    Ok(())
+}
+
+// plays the tones of a frequency script until its end or until playback is stopped
+async fn play_freq_script(
+    args: &args::Args,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let script = freq_script::FreqScript::load(path).await?;
+
+    let device = open_device(args).await?;
+    let (device, writer) =
+        device::spawn_writer(device, args.zero_velocity, args.play_options.soft_reset);
+
+    let (stop_tx, stop_rx) = watch::channel(false);
+    tokio::spawn(handle_stop(stop_tx, keys::KeyReader::spawn()?));
+
+    info!("playing {} tone(s)", script.steps.len());
+    if let Err(e) = play::play_freq_script(script, device.clone(), stop_rx.clone()).await {
+        return Err(reset_after_failure(device, writer, e).await);
+    }
+
+    let reset = if *stop_rx.borrow() {
+        device.reset(Instant::now()).await
+    } else {
+        Ok(())
+    };
+
+    // writes only fail once the writer has stopped, so its error comes first
+    drop(device);
+    writer.await??;
+    reset?;
+
+    Ok(())
+}
+
+// resets the device once playback has failed and waits for the writer to write out the reset,
+// so that nothing is left sounding. The handle has to be the last one left. Returns the error to
+// report, which is the one the writer stopped with if that is why playback failed
+async fn reset_after_failure(
+    device: device::DeviceHandle,
+    writer: device::WriterHandle,
+    e: Box<dyn std::error::Error + Send + Sync>,
+) -> Box<dyn std::error::Error + Send + Sync> {
+    let writer_stopped = device.writer_stopped();
+    if !writer_stopped {
+        if let Err(e) = device.reset(Instant::now()).await {
+            warn!("failed to reset the device after playback failed: {e}");
+        }
+    }
+
+    drop(device);
+    let writer_e: Box<dyn std::error::Error + Send + Sync> = match writer.await {
+        Ok(Ok(_)) => return e,
+        Ok(Err(writer_e)) => writer_e,
+        Err(join_e) => join_e.into(),
+    };
+    if writer_stopped {
+        return writer_e;
+    }
+
+    warn!("failed to write to the device after playback failed: {writer_e}");
+    e
+}
+
+async fn open_device(
+    args: &args::Args,
+) -> Result<device::BoxedDevice, Box<dyn std::error::Error + Send + Sync>> {
+    #[cfg(all(feature = "pwm", target_os = "linux"))]
+    if let (Some((chip, channel)), false) = (args.pwm, args.dry_run) {
+        return Ok(Box::new(pwm::PwmDevice::new(chip, channel).await?));
+    }
+
+    if let (Some((path, channel)), false) = (&args.midi_out, args.dry_run) {
+        return Ok(Box::new(
+            midi_out::MidiOutDevice::open(path, *channel).await?,
+        ));
+    }
+
+    device::new(args.dry_run, args.dump_protocol, args.serial.clone()).await
+}
+
+async fn open_osc(
+    args: &args::Args,
+) -> Result<Option<Arc<osc::OscSink>>, Box<dyn std::error::Error + Send + Sync>> {
+    match &args.osc {
+        Some(target) => {
+            info!("sending notes to {target} over OSC");
+            Ok(Some(Arc::new(osc::OscSink::connect(target).await?)))
+        }
+        None => Ok(None),
+    }
+}
+
+// reads the positions to jump to for --scrub from stdin, one per line, in seconds or in ticks
+// when suffixed with `t`. Ends with stdin or once playback is over
+fn read_positions(tempo_map: TempoMap, seek_tx: mpsc::UnboundedSender<Seek>) {
+    for line in std::io::stdin().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let tick = match line.strip_suffix('t') {
+            Some(ticks) => ticks.trim().parse::<u64>().ok(),
+            None => line
+                .strip_suffix('s')
+                .unwrap_or(line)
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                .map(|time| tempo_map.tick_at(time)),
+        };
+        let Some(tick) = tick else {
+            warn!("ignoring invalid position `{line}`");
+            continue;
+        };
+
+        debug!("jumping to tick {tick}");
+        let seek = Seek {
+            tick,
+            tick_us: tempo_map.us_per_tick_at(tick).round() as u32,
+        };
+        if seek_tx.send(seek).is_err() {
+            break;
+        }
+    }
+}
+
+// how often the watchdog of --stuck-note-timeout looks at the notes
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+// resets the device once notes have been left sounding with nothing starting or stopping for
+// `timeout`, for --stuck-note-timeout. Only once for every such stretch, the notes the tracks
+// still hold count until the tracks release them
+async fn watch_stuck_notes(
+    instrument_count: Arc<Mutex<InstrumentCount>>,
+    device: device::DeviceHandle,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut handled = None;
+
+    loop {
+        tokio::time::sleep(WATCHDOG_INTERVAL).await;
+
+        let count = *instrument_count.lock().await;
+        let Some(last_change) = count.last_change else {
+            continue;
+        };
+
+        if count.current > 0 && last_change.elapsed() >= timeout && handled != Some(last_change) {
+            warn!(
+                "{} note(s) sounding with nothing happening for {:.1} s, resetting the device",
+                count.current,
+                timeout.as_secs_f64()
+            );
+            device.reset(Instant::now()).await?;
+            handled = Some(last_change);
+        }
+    }
+}
+
+// asks playback to stop on Ctrl-C or a stop key, the tracks release their notes and playback
+// returns so that the device can be reset once nobody is writing to it anymore
+async fn handle_stop(
+    stop_tx: watch::Sender<bool>,
+    mut keys: Option<keys::KeyReader>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let stop_key = async {
+        match keys.as_mut() {
+            Some(keys) => {
+                while let Some(key) = keys.next().await {
+                    if matches!(key, keys::Key::Char('q') | keys::Key::Escape) {
+                        return;
+                    }
+                }
+                std::future::pending().await
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    tokio::select! {
+        ctrl_c = tokio::signal::ctrl_c() => ctrl_c?,
+        _ = stop_key => (),
+    }
+
+    stop_tx.send(true)?;
 
     Ok(())
 }
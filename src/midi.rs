@@ -1,6 +1,7 @@
-use std::{path::Path, process::exit, time::Duration};
+use std::{path::Path, time::Duration};
 
-use midly::{MetaMessage, Smf, TrackEvent, TrackEventKind};
+use log::{info, warn};
+use midly::{EventIter, TrackEvent};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Timing {
@@ -8,24 +9,171 @@ pub struct Timing {
     pub tick: Duration,
 }
 
-pub fn deduce_timing(timing: &midly::Timing, initial_tick: Option<Duration>) -> Timing {
+/// How to read the division of the header of a file, regardless of what it says
+#[derive(Debug, Clone, Copy)]
+pub enum TimingMode {
+    /// ticks per beat, those of --ticks-per-beat or the low 15 bits of the division
+    Metrical,
+    Timecode {
+        fps: midly::Fps,
+        ticks_per_frame: u8,
+    },
+}
+
+/// Settings of the command line that replace the timing of the file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimingOverrides {
+    pub initial_tick: Option<Duration>,
+    pub ticks_per_beat: Option<u32>,
+    pub mode: Option<TimingMode>,
+    pub default_bpm: Option<f64>,
+    pub tempo_track: Option<usize>,
+}
+
+/// The tempos played at in µs per beat, 6000 down to 6 bpm. A corrupt tempo update outside of
+/// them, like one of 0, would otherwise stop time or stall playback.
+pub const TEMPO_RANGE: std::ops::RangeInclusive<u32> = 10_000..=10_000_000;
+
+/// Clamps a tempo in µs per beat to `TEMPO_RANGE`
+pub fn clamp_tempo(us_per_beat: u32) -> u32 {
+    us_per_beat.clamp(*TEMPO_RANGE.start(), *TEMPO_RANGE.end())
+}
+
+// the tick assumed for metrically timed files without an initial tempo, unless --default-bpm
+// is given
+const ASSUMED_TICK: Duration = Duration::from_micros(500);
+
+// reads the division of the header the way the timing mode says, for files whose header has
+// the wrong kind of division
+fn override_timing(timing: midly::Timing, mode: Option<TimingMode>) -> midly::Timing {
+    match (timing, mode) {
+        (midly::Timing::Timecode(fps, ticks_per_frame), Some(TimingMode::Metrical)) => {
+            // the division as it is stored in the file, with the negated fps in the high byte
+            let division = ((-(fps.as_int() as i8)) as u8 as u16) << 8 | ticks_per_frame as u16;
+            let ticks_per_beat = midly::num::u15::new(division & 0x7fff);
+
+            warn!(
+                "the header has timecode timing, reading its division as {ticks_per_beat} ticks per beat instead"
+            );
+            midly::Timing::Metrical(ticks_per_beat)
+        }
+        (
+            midly::Timing::Metrical(ticks_per_beat),
+            Some(TimingMode::Timecode {
+                fps,
+                ticks_per_frame,
+            }),
+        ) => {
+            warn!(
+                "the header has metrical timing of {ticks_per_beat} ticks per beat, using timecode of {} fps and {ticks_per_frame} ticks per frame instead",
+                fps.as_int()
+            );
+            midly::Timing::Timecode(fps, ticks_per_frame)
+        }
+        (
+            midly::Timing::Timecode(_, _),
+            Some(TimingMode::Timecode {
+                fps,
+                ticks_per_frame,
+            }),
+        ) => {
+            warn!(
+                "replacing the timecode timing of the header with {} fps and {ticks_per_frame} ticks per frame",
+                fps.as_int()
+            );
+            midly::Timing::Timecode(fps, ticks_per_frame)
+        }
+        (timing, _) => timing,
+    }
+}
+
+// a division of 0 ticks would make every tick infinitely long, which can't be played
+fn check_division(
+    timing: &midly::Timing,
+    ticks_per_beat_override: Option<u32>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if ticks_per_beat_override == Some(0) {
+        return Err("the ticks per beat can't be overridden with 0".into());
+    }
+
+    match timing {
+        midly::Timing::Metrical(ticks_per_beat)
+            if ticks_per_beat.as_int() == 0 && ticks_per_beat_override.is_none() =>
+        {
+            Err("the header has a division of 0 ticks per beat".into())
+        }
+        midly::Timing::Timecode(_, 0) => {
+            Err("the header has a division of 0 ticks per frame".into())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn deduce_timing(
+    timing: &midly::Timing,
+    initial_tick: Option<Duration>,
+    ticks_per_beat_override: Option<u32>,
+    initial_us_per_beat: Option<u32>,
+    default_bpm: Option<f64>,
+) -> Timing {
     match timing {
         midly::Timing::Metrical(a) => {
-            println!("timing = metrical: {a}");
+            info!("timing = metrical: {a}");
+
+            let file_ticks_per_beat: u32 = <midly::num::u15 as Into<u16>>::into(*a).into();
 
-            let ticks_per_beat = <midly::num::u15 as Into<u16>>::into(*a).into();
+            let ticks_per_beat = match ticks_per_beat_override {
+                Some(ticks_per_beat) => {
+                    warn!(
+                        "overriding the file's {file_ticks_per_beat} ticks per beat with {ticks_per_beat}"
+                    );
+                    ticks_per_beat
+                }
+                None => file_ticks_per_beat,
+            };
 
-            println!("ticks per beat: {ticks_per_beat}");
+            info!("ticks per beat: {ticks_per_beat}");
             if let Some(override_tick) = initial_tick {
-                println!("using provided tick: {} µs", override_tick.as_micros());
+                info!("using provided tick: {} µs", override_tick.as_micros());
 
                 Timing {
                     ticks_per_beat,
                     tick: override_tick,
                 }
+            } else if let Some(us_per_beat) = initial_us_per_beat {
+                if !TEMPO_RANGE.contains(&us_per_beat) {
+                    warn!("the initial tempo of {us_per_beat} µs per beat is out of range, clamping it");
+                }
+                let us_per_beat = clamp_tempo(us_per_beat);
+                let tick = Duration::from_micros(us_per_beat.into()) / ticks_per_beat;
+                info!(
+                    "initial tempo: {us_per_beat} µs per beat, tick: {} µs",
+                    tick.as_micros()
+                );
+
+                Timing {
+                    ticks_per_beat,
+                    tick,
+                }
+            } else if let Some(bpm) = default_bpm {
+                let tick = Duration::from_secs_f64(60.0 / bpm) / ticks_per_beat;
+                info!(
+                    "no initial tempo, assuming {bpm} bpm, tick: {} µs",
+                    tick.as_micros()
+                );
+
+                Timing {
+                    ticks_per_beat,
+                    tick,
+                }
             } else {
-                let assumed_tick = Duration::from_micros(500);
-                println!("assuming initial tick: {} µs", assumed_tick.as_micros());
+                // keep the assumed beat length when the ticks per beat are overridden, a header
+                // without a division has none to keep
+                let assumed_tick = match file_ticks_per_beat {
+                    0 => ASSUMED_TICK,
+                    _ => ASSUMED_TICK * file_ticks_per_beat / ticks_per_beat,
+                };
+                info!("assuming initial tick: {} µs", assumed_tick.as_micros());
 
                 Timing {
                     ticks_per_beat,
@@ -34,14 +182,18 @@ pub fn deduce_timing(timing: &midly::Timing, initial_tick: Option<Duration>) ->
             }
         }
         midly::Timing::Timecode(fps, subframe) => {
-            println!("timing = timecode: {}, {}", fps.as_int(), subframe);
+            info!("timing = timecode: {}, {}", fps.as_int(), subframe);
+
+            if ticks_per_beat_override.is_some() {
+                warn!("ticks per beat can only be overridden for metrical timing, ignoring");
+            }
 
             let ticks_per_beat = *subframe as u32;
             let tick = Duration::from_micros(1000000 / (fps.as_int() as u64 * *subframe as u64));
 
-            println!("ticks per beat: {ticks_per_beat}");
+            info!("ticks per beat: {ticks_per_beat}");
             if let Some(override_tick) = initial_tick {
-                println!(
+                info!(
                     "found initial tick: {} µs but using provided tick of {} µs",
                     tick.as_micros(),
                     override_tick.as_micros()
@@ -51,7 +203,7 @@ pub fn deduce_timing(timing: &midly::Timing, initial_tick: Option<Duration>) ->
                     tick: override_tick,
                 }
             } else {
-                println!("initial tick: {} µs", tick.as_micros());
+                info!("initial tick: {} µs", tick.as_micros());
 
                 Timing {
                     ticks_per_beat,
@@ -70,122 +222,910 @@ pub struct Event {
 
 #[derive(Debug, Clone)]
 pub enum EventKind {
-    NoteUpdate { key: u8, vel: u8 },
+    // the release velocity of note offs that have one, not of note ons with a velocity of 0
+    NoteUpdate {
+        channel: u8,
+        key: u8,
+        vel: u8,
+        release_vel: Option<u8>,
+    },
+    Aftertouch {
+        key: Option<u8>,
+        value: u8,
+    },
+    Controller {
+        controller: u8,
+        value: u8,
+    },
     TempoUpdate(u32),
     TrackName(String),
     TrackInstrument(String),
+    Directive(Directive),
+    // where the sequence starts on the timeline of the SMPTE time code
+    SmpteOffset(Duration),
+    // keys retuned by MIDI Tuning Standard SysEx, to pitches in semitones from key 0
+    Tuning(Vec<(u8, f64)>),
 }
 
-pub fn convert<'a, I: IntoIterator<Item = &'a TrackEvent<'a>>, B: FromIterator<Event>>(
-    track: I,
-) -> B {
-    track
-        .into_iter()
-        .map(|track_event| Event {
-            delta: track_event.delta.into(),
-            kind: match track_event.kind {
-                midly::TrackEventKind::Midi {
-                    channel: _,
-                    message,
-                } => match message {
-                    midly::MidiMessage::NoteOff { key, vel: _ } => Some(EventKind::NoteUpdate {
-                        key: key.into(),
-                        vel: 0,
-                    }),
-                    midly::MidiMessage::NoteOn { key, vel } => Some(EventKind::NoteUpdate {
-                        key: key.into(),
-                        vel: vel.into(),
-                    }),
-                    _ => None,
-                },
-                midly::TrackEventKind::Meta(m) => match m {
-                    midly::MetaMessage::Tempo(t) => Some(EventKind::TempoUpdate(t.into())),
-                    midly::MetaMessage::TrackName(bytes) => Some(EventKind::TrackName(
-                        String::from_utf8_lossy(bytes).to_string(),
-                    )),
-                    midly::MetaMessage::InstrumentName(bytes) => Some(EventKind::TrackInstrument(
-                        String::from_utf8_lossy(bytes).to_string(),
-                    )),
-                    _ => None,
-                },
+/// The kinds of events playback acts on, for --enable-events
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventClass {
+    Note,
+    Aftertouch,
+    Controller,
+    Tempo,
+    Directive,
+    Tuning,
+}
+
+impl EventKind {
+    /// None for the events that only describe the file
+    pub fn class(&self) -> Option<EventClass> {
+        match self {
+            EventKind::NoteUpdate { .. } => Some(EventClass::Note),
+            EventKind::Aftertouch { .. } => Some(EventClass::Aftertouch),
+            EventKind::Controller { .. } => Some(EventClass::Controller),
+            EventKind::TempoUpdate(_) => Some(EventClass::Tempo),
+            EventKind::Directive(_) => Some(EventClass::Directive),
+            EventKind::Tuning(_) => Some(EventClass::Tuning),
+            EventKind::TrackName(_) | EventKind::TrackInstrument(_) | EventKind::SmpteOffset(_) => {
+                None
+            }
+        }
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// Which octave key 60 is named in, as DAWs disagree. Only changes the names that are printed,
+/// not the frequencies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OctaveNaming {
+    /// key 60 is C4
+    #[default]
+    C4,
+    /// key 60 is C5
+    C5,
+}
+
+/// The name of a key like `C#4`, with the octave numbered as `naming` says
+pub fn key_name(key: u8, naming: OctaveNaming) -> String {
+    let octave = match naming {
+        OctaveNaming::C4 => key as i32 / 12 - 1,
+        OctaveNaming::C5 => key as i32 / 12,
+    };
+
+    format!("{}{octave}", NOTE_NAMES[key as usize % 12])
+}
+
+/// A playback instruction embedded in a text event as `@name [argument]`
+#[derive(Debug, Clone)]
+pub enum Directive {
+    /// ignore tempo updates from here on
+    TempoLock,
+    /// follow tempo updates again
+    TempoUnlock,
+    /// scale the velocity of the notes of the track from here on
+    Volume(f64),
+    Unknown(String),
+}
+
+// text events that don't start with `@` are just text
+fn parse_directive(text: &str) -> Option<Directive> {
+    let text = text.trim().strip_prefix('@')?;
+    let mut words = text.split_whitespace();
+
+    Some(match (words.next(), words.next(), words.next()) {
+        (Some("tempo-lock"), None, _) => Directive::TempoLock,
+        (Some("tempo-unlock"), None, _) => Directive::TempoUnlock,
+        (Some("volume"), Some(volume), None) => match volume.parse::<f64>() {
+            Ok(volume) if volume.is_finite() && volume >= 0.0 => Directive::Volume(volume),
+            _ => Directive::Unknown(text.into()),
+        },
+        _ => Directive::Unknown(text.into()),
+    })
+}
+
+// a pitch of the MIDI Tuning Standard, a semitone and a 14 bit fraction of the next one.
+// 7F 7F 7F leaves the key as it is
+fn tuning_pitch(data: &[u8]) -> Option<f64> {
+    match *data {
+        [0x7f, 0x7f, 0x7f] => None,
+        [semitone, msb, lsb] => {
+            Some(semitone as f64 + (((msb as u16) << 7) | lsb as u16) as f64 / 16384.0)
+        }
+        _ => None,
+    }
+}
+
+// single note tuning changes and bulk tuning dumps of the MIDI Tuning Standard, other SysEx
+// isn't parsed. The tuning program and bank are ignored, every tuning applies right away
+fn parse_tuning(data: &[u8]) -> Option<Vec<(u8, f64)>> {
+    let data = data.strip_suffix(&[0xf7]).unwrap_or(data);
+
+    // universal SysEx: real time or not, the device ID and the sub IDs, followed by the bank
+    // for the messages that have one, the program, and the note count of single note changes
+    let (notes, pitches) = match data {
+        [0x7e | 0x7f, _, 0x08, 0x02, _, _, notes @ ..]
+        | [0x7e | 0x7f, _, 0x08, 0x07, _, _, _, notes @ ..] => (notes, None),
+        [0x7e, _, 0x08, 0x01, _, rest @ ..] | [0x7e, _, 0x08, 0x04, _, _, rest @ ..] => {
+            (&[][..], Some(rest.get(16..16 + 128 * 3)?))
+        }
+        _ => return None,
+    };
+
+    let changes = match pitches {
+        // a bulk dump has the name of the tuning and then the pitch of every key
+        Some(pitches) => pitches
+            .chunks_exact(3)
+            .enumerate()
+            .filter_map(|(key, pitch)| Some((key as u8, tuning_pitch(pitch)?)))
+            .collect(),
+        None => notes
+            .chunks_exact(4)
+            .filter_map(|note| Some((note[0], tuning_pitch(&note[1..])?)))
+            .collect(),
+    };
+
+    Some(changes)
+}
+
+pub fn convert(track_event: &TrackEvent) -> Event {
+    Event {
+        delta: track_event.delta.into(),
+        kind: match track_event.kind {
+            midly::TrackEventKind::Midi { channel, message } => match message {
+                midly::MidiMessage::NoteOff { key, vel } => Some(EventKind::NoteUpdate {
+                    channel: channel.into(),
+                    key: key.into(),
+                    vel: 0,
+                    release_vel: Some(vel.into()),
+                }),
+                midly::MidiMessage::NoteOn { key, vel } => Some(EventKind::NoteUpdate {
+                    channel: channel.into(),
+                    key: key.into(),
+                    vel: vel.into(),
+                    release_vel: None,
+                }),
+                midly::MidiMessage::Aftertouch { key, vel } => Some(EventKind::Aftertouch {
+                    key: Some(key.into()),
+                    value: vel.into(),
+                }),
+                midly::MidiMessage::ChannelAftertouch { vel } => Some(EventKind::Aftertouch {
+                    key: None,
+                    value: vel.into(),
+                }),
+                midly::MidiMessage::Controller { controller, value } => {
+                    Some(EventKind::Controller {
+                        controller: controller.into(),
+                        value: value.into(),
+                    })
+                }
                 _ => None,
             },
+            midly::TrackEventKind::Meta(m) => match m {
+                midly::MetaMessage::Tempo(t) => Some(EventKind::TempoUpdate(t.into())),
+                midly::MetaMessage::TrackName(bytes) => Some(EventKind::TrackName(
+                    String::from_utf8_lossy(bytes).to_string(),
+                )),
+                midly::MetaMessage::InstrumentName(bytes) => Some(EventKind::TrackInstrument(
+                    String::from_utf8_lossy(bytes).to_string(),
+                )),
+                midly::MetaMessage::Text(bytes) => {
+                    parse_directive(&String::from_utf8_lossy(bytes)).map(EventKind::Directive)
+                }
+                midly::MetaMessage::SmpteOffset(time) => {
+                    Some(EventKind::SmpteOffset(smpte_duration(&time)))
+                }
+                _ => None,
+            },
+            midly::TrackEventKind::SysEx(data) => parse_tuning(data).map(EventKind::Tuning),
+            _ => None,
+        },
+    }
+}
+
+fn smpte_duration(time: &midly::SmpteTime) -> Duration {
+    let frames = time.frame() as f64 + time.subframe() as f64 / 100.0;
+
+    Duration::from_secs(time.hour() as u64 * 3600 + time.minute() as u64 * 60)
+        + Duration::from_secs_f64(time.second() as f64 + frames / time.fps().as_f32() as f64)
+}
+
+/// A track that converts its raw events into `Event`s lazily, as it is iterated.
+///
+/// Cloning a track is cheap, the clone starts over from the position of the original.
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub index: usize,
+    events: EventIter<'static>,
+    // added to every key, for --key-offset
+    key_offset: i8,
+    // the ticks still to be cut from the start of the track, for --align-first-note
+    lead_in: u64,
+    // the only channel whose events are played, for --split-track-channels
+    channel: Option<u8>,
+    // whether the events without a channel are played along with those of `channel`
+    channelless_events: bool,
+    // whether the tempo updates of the track are followed, for --tempo-track
+    tempo_updates: bool,
+}
+
+impl Track {
+    fn new(index: usize, events: EventIter<'static>) -> Self {
+        Self {
+            index,
+            events,
+            key_offset: 0,
+            lead_in: 0,
+            channel: None,
+            channelless_events: true,
+            tempo_updates: true,
+        }
+    }
+
+    // converts with the key offset applied, clamping the keys to 0 to 127. Events that are
+    // split off into other tracks and ignored tempo updates convert to nothing, keeping their
+    // delta
+    fn convert(&self, track_event: &TrackEvent) -> Event {
+        let mut event = convert(track_event);
+
+        let kept = match (self.channel, track_event.kind) {
+            (None, _) => true,
+            (Some(kept), midly::TrackEventKind::Midi { channel, .. }) => channel == kept,
+            (Some(_), _) => self.channelless_events,
+        };
+        let ignored_tempo =
+            !self.tempo_updates && matches!(event.kind, Some(EventKind::TempoUpdate(_)));
+        if !kept || ignored_tempo {
+            event.kind = None;
+        }
+
+        let offset_key = |key: &mut u8| {
+            *key = (*key as i16 + self.key_offset as i16).clamp(0, 127) as u8;
+        };
+
+        match &mut event.kind {
+            Some(EventKind::NoteUpdate { key, .. }) => offset_key(key),
+            Some(EventKind::Aftertouch { key: Some(key), .. }) => offset_key(key),
+            _ => (),
+        }
+
+        event
+    }
+
+    /// The name of the track, along with the channel for tracks split by channel
+    pub fn name(&self) -> Option<String> {
+        let mut whole = Track {
+            channel: None,
+            ..self.clone()
+        };
+        let name = whole.find_map(|e| match e.kind {
+            Some(EventKind::TrackName(name)) => Some(name),
+            _ => None,
+        })?;
+
+        Some(match self.channel {
+            Some(channel) => format!("{name} (channel {channel})"),
+            None => name,
         })
-        .collect()
+    }
+
+    /// The channels of the notes of the track, in order
+    pub fn channels(&self) -> Vec<u8> {
+        let mut channels = self
+            .clone()
+            .filter_map(|e| match e.kind {
+                Some(EventKind::NoteUpdate { channel, .. }) => Some(channel),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        channels.sort();
+        channels.dedup();
+        channels
+    }
+
+    pub fn instrument(&self) -> Option<String> {
+        self.clone().find_map(|e| match e.kind {
+            Some(EventKind::TrackInstrument(instrument)) => Some(instrument),
+            _ => None,
+        })
+    }
+
+    pub fn smpte_offset(&self) -> Option<Duration> {
+        self.clone().find_map(|e| match e.kind {
+            Some(EventKind::SmpteOffset(offset)) => Some(offset),
+            _ => None,
+        })
+    }
+
+    /// The lowest and highest key the track plays
+    pub fn key_range(&self) -> Option<(u8, u8)> {
+        self.clone()
+            .filter_map(|e| match e.kind {
+                Some(EventKind::NoteUpdate { key, vel, .. }) if vel != 0 => Some(key),
+                _ => None,
+            })
+            .fold(None, |range, key| match range {
+                Some((lowest, highest)) => Some((key.min(lowest), key.max(highest))),
+                None => Some((key, key)),
+            })
+    }
+
+    /// The raw events of the track paired with what they convert to. An event that fails to
+    /// parse ends the track, as in playback.
+    pub fn raw_events(&self) -> impl Iterator<Item = (TrackEvent<'static>, Event)> {
+        let track = self.clone();
+        self.events
+            .clone()
+            .map_while(Result::ok)
+            .map(move |track_event| (track_event, track.convert(&track_event)))
+    }
+
+    /// The tick of the first note of the track, or of the first event playback acts on for
+    /// tracks without notes
+    pub fn first_event_tick(&self) -> Option<u64> {
+        let first_note = self
+            .clone()
+            .with_ticks()
+            .find_map(|(tick, e)| match e.kind {
+                Some(EventKind::NoteUpdate { vel, .. }) if vel != 0 => Some(tick),
+                _ => None,
+            });
+
+        first_note.or_else(|| {
+            self.clone()
+                .with_ticks()
+                .find(|(_, e)| e.kind.as_ref().is_some_and(|kind| kind.class().is_some()))
+                .map(|(tick, _)| tick)
+        })
+    }
+
+    /// Pairs every event with the absolute tick it happens at
+    pub fn with_ticks(self) -> impl Iterator<Item = (u64, Event)> {
+        self.scan(0u64, |tick, event| {
+            *tick += event.delta as u64;
+            Some((*tick, event))
+        })
+    }
 }
 
-fn get_track_name_raw<'a, I: Iterator<Item = &'a TrackEvent<'a>>>(track: I) -> Option<String> {
-    for i in track {
-        if let TrackEventKind::Meta(MetaMessage::TrackName(name_slice)) = i.kind {
-            return Some(String::from_utf8_lossy(name_slice).to_string());
+impl Iterator for Track {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.events.next()? {
+            Ok(track_event) => {
+                let mut event = self.convert(&track_event);
+
+                let cut = self.lead_in.min(event.delta as u64);
+                event.delta -= cut as u32;
+                self.lead_in -= cut;
+
+                Some(event)
+            }
+            Err(e) => {
+                warn!(
+                    "track {}: failed to parse event: {e}, ending track",
+                    self.index
+                );
+                None
+            }
         }
     }
-    None
 }
 
-fn get_track_instrument_raw<'a, I: Iterator<Item = &'a TrackEvent<'a>>>(
-    track: I,
-) -> Option<String> {
-    for i in track {
-        if let TrackEventKind::Meta(MetaMessage::InstrumentName(name_slice)) = i.kind {
-            return Some(String::from_utf8_lossy(name_slice).to_string());
+/// Maps absolute ticks to the time they are played at, following the tempo updates of all the
+/// given tracks the same way playback does
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    // (first tick, µs per tick) for every stretch of constant tempo, sorted by tick
+    segments: Vec<(u64, f64)>,
+}
+
+impl TempoMap {
+    pub fn new(tracks: &[Track], timing: Timing, tempo: f64) -> Self {
+        let mut segments = vec![(0, timing.tick.as_micros() as f64)];
+
+        segments.extend(tracks.iter().flat_map(|track| {
+            track
+                .clone()
+                .with_ticks()
+                .filter_map(move |(tick, event)| match event.kind {
+                    Some(EventKind::TempoUpdate(us_per_beat)) => Some((
+                        tick,
+                        clamp_tempo(us_per_beat) as f64 / timing.ticks_per_beat as f64 / tempo,
+                    )),
+                    _ => None,
+                })
+        }));
+
+        segments.sort_by_key(|(tick, _)| *tick);
+
+        Self { segments }
+    }
+
+    /// Follows tick lengths in µs that are switched to at the given times, e.g. those of a
+    /// `TempoFile`, instead of the tempo updates of the tracks
+    pub fn from_schedule(schedule: &[(Duration, u32)]) -> Self {
+        let mut segments = Vec::new();
+        let mut tick = 0.0f64;
+
+        for (i, (time, tick_us)) in schedule.iter().enumerate() {
+            segments.push((tick.round() as u64, *tick_us as f64));
+
+            if let Some((next_time, _)) = schedule.get(i + 1) {
+                tick += (*next_time - *time).as_secs_f64() * 1_000_000.0 / *tick_us as f64;
+            }
+        }
+
+        Self { segments }
+    }
+
+    pub fn time_at(&self, tick: u64) -> Duration {
+        let mut us = 0.0;
+
+        for (i, (start, us_per_tick)) in self.segments.iter().enumerate() {
+            if *start >= tick {
+                break;
+            }
+
+            let end = self
+                .segments
+                .get(i + 1)
+                .map_or(tick, |(next_start, _)| (*next_start).min(tick));
+
+            us += (end - start) as f64 * us_per_tick;
+        }
+
+        Duration::from_secs_f64(us / 1_000_000.0)
+    }
+
+    /// The first tick that is played at or after `time`
+    pub fn tick_at(&self, time: Duration) -> u64 {
+        let target_us = time.as_secs_f64() * 1_000_000.0;
+        let mut us = 0.0;
+
+        for (i, (start, us_per_tick)) in self.segments.iter().enumerate() {
+            if let Some((next_start, _)) = self.segments.get(i + 1) {
+                let segment_us = (next_start - start) as f64 * us_per_tick;
+                if us + segment_us < target_us {
+                    us += segment_us;
+                    continue;
+                }
+            }
+
+            return start + ((target_us - us) / us_per_tick).ceil() as u64;
+        }
+
+        unreachable!("a tempo map always has at least one segment")
+    }
+
+    pub fn us_per_tick_at(&self, tick: u64) -> f64 {
+        self.segments
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= tick)
+            .map_or(self.segments[0].1, |(_, us_per_tick)| *us_per_tick)
+    }
+}
+
+/// The tempo in effect when the first note of any track starts, so that playback doesn't begin
+/// at the assumed tempo until the tempo update is reached. Of several tempo updates at the same
+/// tick the last one wins, as it does during playback.
+fn initial_tempo(tracks: &[Track]) -> Option<u32> {
+    let first_note_tick = tracks
+        .iter()
+        .filter_map(|track| {
+            track
+                .clone()
+                .with_ticks()
+                .find_map(|(tick, event)| match event.kind {
+                    Some(EventKind::NoteUpdate { vel, .. }) if vel != 0 => Some(tick),
+                    _ => None,
+                })
+        })
+        .min()
+        .unwrap_or(0);
+
+    let mut tempos = tracks
+        .iter()
+        .flat_map(|track| {
+            track
+                .clone()
+                .with_ticks()
+                .take_while(move |(tick, _)| *tick <= first_note_tick)
+                .filter_map(|(tick, event)| match event.kind {
+                    Some(EventKind::TempoUpdate(us_per_beat)) => Some((tick, us_per_beat)),
+                    _ => None,
+                })
+        })
+        .collect::<Vec<_>>();
+
+    tempos.sort_by_key(|(tick, _)| *tick);
+    tempos.last().map(|(_, us_per_beat)| *us_per_beat)
+}
+
+// a selected track without notes plays nothing, which is most likely a wrong selection unless
+// it is the conductor track
+fn check_selected_track(
+    track: &Track,
+    strict: bool,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut has_tempo = false;
+    for event in track.clone() {
+        match event.kind {
+            Some(EventKind::NoteUpdate { vel, .. }) if vel != 0 => return Ok(()),
+            Some(EventKind::TempoUpdate(_)) => has_tempo = true,
+            _ => (),
         }
     }
-    None
+
+    let message = if has_tempo {
+        format!(
+            "selected track {} has no notes, only tempo updates, it is probably a conductor track",
+            track.index
+        )
+    } else {
+        format!("selected track {} has no notes", track.index)
+    };
+
+    if strict {
+        return Err(message.into());
+    }
+    warn!("{message}");
+
+    Ok(())
 }
 
 pub struct MidiSequence {
+    pub header: midly::Header,
     pub timing: Timing,
-    pub tracks: Vec<Vec<Event>>,
+    pub tracks: Vec<Track>,
+    // how many tracks the header declares, selected or not
+    pub track_count: usize,
 }
 
 impl MidiSequence {
     pub async fn parse_file(
         path: impl AsRef<Path>,
         track_indices: Option<impl Iterator<Item = usize>>,
-        initial_tick: Option<Duration>,
+        timing_overrides: TimingOverrides,
         list: bool,
+        strict: bool,
+        lenient: bool,
     ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
-        let file_buf = tokio::fs::read(path).await?;
+        // the tracks are parsed lazily during playback and borrow from the file buffer, so it
+        // is leaked to let them be moved into the playback task. It has to live for the rest
+        // of the process anyway.
+        let file_buf: &'static [u8] = Box::leak(tokio::fs::read(path).await?.into_boxed_slice());
 
-        let raw_midi = Smf::parse(&file_buf)?;
+        let (header, raw_tracks) = midly::parse(file_buf)?;
+        // the track count of the header
+        let declared_tracks = raw_tracks.size_hint().0;
 
-        let timing = deduce_timing(&raw_midi.header.timing, initial_tick);
+        let mut tracks = if lenient {
+            raw_tracks
+                .enumerate()
+                .filter_map(|(index, events)| match events {
+                    Ok(events) => Some(Track::new(index, events)),
+                    Err(e) => {
+                        warn!("track {index}: failed to parse: {e}, skipping it");
+                        None
+                    }
+                })
+                .collect::<Vec<_>>()
+        } else {
+            raw_tracks
+                .enumerate()
+                .map(|(index, events)| events.map(|events| Track::new(index, events)))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        // there would be nothing to play
+        if tracks.is_empty() {
+            return Err(match declared_tracks {
+                0 => "file contains no tracks".into(),
+                n => format!("the header declares {n} track(s), but none could be read").into(),
+            });
+        }
+        if tracks.len() != declared_tracks {
+            warn!(
+                "the header declares {declared_tracks} track(s), but {} were read",
+                tracks.len()
+            );
+        }
+
+        if let Some(n) = timing_overrides.tempo_track {
+            if !tracks.iter().any(|track| track.index == n) {
+                return Err(format!("tempo track {n} does not exist or failed to parse").into());
+            }
+
+            for track in &mut tracks {
+                track.tempo_updates = track.index == n;
+            }
+            info!("following the tempo updates of track {n} only");
+        }
+
+        let file_timing = override_timing(header.timing, timing_overrides.mode);
+        check_division(&file_timing, timing_overrides.ticks_per_beat)?;
 
-        println!(
-            "file contains {} track(s), listing...",
-            raw_midi.tracks.len()
+        let timing = deduce_timing(
+            &file_timing,
+            timing_overrides.initial_tick,
+            timing_overrides.ticks_per_beat,
+            initial_tempo(&tracks),
+            timing_overrides.default_bpm,
         );
 
-        for (i, raw_track) in raw_midi.tracks.iter().enumerate() {
-            let name = get_track_name_raw(raw_track.iter()).unwrap_or_else(|| "Unknown".into());
-            let instrument =
-                get_track_instrument_raw(raw_track.iter()).unwrap_or_else(|| "Unknown".into());
+        info!("file contains {} track(s), listing...", tracks.len());
 
-            println!("{i:<2} - name: {name:<32} - instrument: {instrument}");
+        for track in tracks.iter() {
+            let name = track.name().unwrap_or_else(|| "Unknown".into());
+            let instrument = track.instrument().unwrap_or_else(|| "Unknown".into());
+            let line = format!(
+                "{:<2} - name: {name:<32} - instrument: {instrument}",
+                track.index
+            );
+
+            // the listing is all a list run puts out, so it isn't hidden along with the log
+            if list {
+                println!("{line}");
+            } else {
+                info!("{line}");
+            }
         }
 
+        // every track is listed, whichever are selected
         if list {
-            println!("list run, quitting...");
-            exit(0);
+            return Ok(Self {
+                header,
+                tracks,
+                timing,
+                track_count: declared_tracks,
+            });
         }
 
         let play_tracks = if let Some(track_indices) = track_indices {
-            track_indices
+            // a track selected twice would play twice in lockstep, so it is only played once
+            let mut selected = Vec::new();
+            for n in track_indices {
+                if selected.contains(&n) {
+                    warn!("track {n} is selected more than once, playing it once");
+                } else {
+                    selected.push(n);
+                }
+            }
+
+            // looked up by index, as skipped tracks leave gaps
+            let play_tracks = selected
                 .into_iter()
-                .map(|n| convert::<_, Vec<_>>(raw_midi.tracks[n].iter()))
-                .collect::<Vec<_>>()
+                .map(|n| {
+                    tracks
+                        .iter()
+                        .find(|track| track.index == n)
+                        .cloned()
+                        .ok_or_else(|| format!("track {n} does not exist or failed to parse"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            for track in play_tracks.iter() {
+                check_selected_track(track, strict)?;
+            }
+
+            play_tracks
         } else {
-            raw_midi
-                .tracks
-                .iter()
-                .map(|raw_track| convert::<_, Vec<_>>(raw_track.iter()))
-                .collect::<Vec<_>>()
+            tracks
         };
 
         Ok(Self {
+            header,
             tracks: play_tracks,
             timing,
+            track_count: declared_tracks,
         })
     }
+
+    /// Adds `offset` to the keys of all notes, for files whose keys are numbered off from the
+    /// standard. Unlike transposing this happens before anything looks at the keys, like the
+    /// drum map.
+    pub fn set_key_offset(&mut self, offset: i8) {
+        for track in &mut self.tracks {
+            track.key_offset = offset;
+        }
+    }
+
+    /// Moves every track earlier so that its first note starts right away, keeping the timing
+    /// of the events after it. The events before the first note all happen at the start.
+    pub fn align_first_notes(&mut self) {
+        for track in &mut self.tracks {
+            track.lead_in = track.first_event_tick().unwrap_or(0);
+        }
+    }
+
+    /// Plays the notes of every channel of the track with the given index as a track of its
+    /// own, for tracks that interleave several parts. The first channel keeps the index and the
+    /// events without a channel, the others are numbered on from the last track. Returns the
+    /// indices of the tracks split off.
+    pub fn split_track_channels(
+        &mut self,
+        index: usize,
+    ) -> Result<Vec<usize>, Box<dyn std::error::Error + Send + Sync>> {
+        let position = self
+            .tracks
+            .iter()
+            .position(|track| track.index == index)
+            .ok_or_else(|| format!("track {index} does not exist or is not selected"))?;
+        let track = self.tracks[position].clone();
+
+        let mut channels = track.channels().into_iter();
+        let Some(first) = channels.next() else {
+            return Ok(Vec::new());
+        };
+        self.tracks[position].channel = Some(first);
+
+        let mut split = Vec::new();
+        for channel in channels {
+            let index = self.track_count;
+            self.track_count += 1;
+
+            self.tracks.push(Track {
+                index,
+                channel: Some(channel),
+                channelless_events: false,
+                ..track.clone()
+            });
+            split.push(index);
+        }
+
+        Ok(split)
+    }
+
+    /// Numbers the tracks from `first_index` on, to tell them apart from those of other
+    /// sequences played along
+    pub fn offset_track_indices(&mut self, first_index: usize) {
+        for track in &mut self.tracks {
+            track.index += first_index;
+        }
+    }
+
+    /// Where the sequence starts on the SMPTE timeline, given by the first track that has an
+    /// offset, which is the first track of well formed files
+    pub fn smpte_offset(&self) -> Option<Duration> {
+        self.tracks.iter().find_map(Track::smpte_offset)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const TICKS_PER_BEAT: u16 = 96;
+
+    pub(crate) fn tempo(us_per_beat: u32) -> Vec<u8> {
+        let [_, a, b, c] = us_per_beat.to_be_bytes();
+        vec![0xff, 0x51, 0x03, a, b, c]
+    }
+
+    pub(crate) fn note(key: u8, vel: u8) -> Vec<u8> {
+        vec![0x90, key, vel]
+    }
+
+    // a format 1 file of the given tracks of (delta, event), each ended for them
+    pub(crate) fn smf(tracks: &[Vec<(u32, Vec<u8>)>]) -> Vec<u8> {
+        let mut file = b"MThd".to_vec();
+        file.extend(6u32.to_be_bytes());
+        file.extend(1u16.to_be_bytes());
+        file.extend((tracks.len() as u16).to_be_bytes());
+        file.extend(TICKS_PER_BEAT.to_be_bytes());
+
+        for track in tracks {
+            let mut data = Vec::new();
+            for (delta, event) in track {
+                let mut delta = *delta;
+                let mut vlq = vec![(delta & 0x7f) as u8];
+                delta >>= 7;
+                while delta != 0 {
+                    vlq.insert(0, (delta & 0x7f) as u8 | 0x80);
+                    delta >>= 7;
+                }
+                data.extend(vlq);
+                data.extend(event);
+            }
+            data.extend([0x00, 0xff, 0x2f, 0x00]);
+
+            file.extend(b"MTrk");
+            file.extend((data.len() as u32).to_be_bytes());
+            file.extend(data);
+        }
+
+        file
+    }
+
+    // parses the file from a temporary path of its own, with nothing overridden
+    pub(crate) async fn parse(
+        name: &str,
+        file: &[u8],
+    ) -> Result<MidiSequence, Box<dyn std::error::Error + Send + Sync>> {
+        let path =
+            std::env::temp_dir().join(format!("play-midi-{}-{name}.mid", std::process::id()));
+        tokio::fs::write(&path, file).await.unwrap();
+
+        let sequence = MidiSequence::parse_file(
+            &path,
+            None::<std::iter::Empty<usize>>,
+            TimingOverrides::default(),
+            false,
+            false,
+            false,
+        )
+        .await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        sequence
+    }
+
+    #[tokio::test]
+    async fn initial_tempo_before_first_note() {
+        let conductor = vec![(0, tempo(1_000_000)), (48, tempo(250_000))];
+        let notes = vec![(96, note(60, 100)), (96, note(60, 0))];
+
+        let sequence = parse("initial-tempo", &smf(&[conductor, notes]))
+            .await
+            .unwrap();
+
+        // the last tempo before the first note, not the first one of the file
+        assert_eq!(
+            sequence.timing.tick,
+            Duration::from_micros(250_000) / TICKS_PER_BEAT as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn initial_tempo_after_first_note_is_not_used() {
+        let notes = vec![(0, note(60, 100)), (96, tempo(250_000)), (0, note(60, 0))];
+
+        let sequence = parse("late-tempo", &smf(&[notes])).await.unwrap();
+
+        assert_eq!(sequence.timing.tick, ASSUMED_TICK);
+    }
+
+    #[tokio::test]
+    async fn no_initial_tempo() {
+        let notes = vec![(0, note(60, 100)), (96, note(60, 0))];
+
+        let sequence = parse("no-tempo", &smf(&[notes])).await.unwrap();
+
+        assert_eq!(sequence.timing.tick, ASSUMED_TICK);
+    }
+
+    #[tokio::test]
+    async fn no_tracks() {
+        let error = parse("no-tracks", &smf(&[])).await.err().unwrap();
+
+        assert_eq!(error.to_string(), "file contains no tracks");
+    }
+
+    #[test]
+    fn clamp_zero_tempo() {
+        assert_eq!(clamp_tempo(0), *TEMPO_RANGE.start());
+        assert_eq!(clamp_tempo(u32::MAX), *TEMPO_RANGE.end());
+        assert_eq!(clamp_tempo(500_000), 500_000);
+    }
+
+    #[tokio::test]
+    async fn tempo_map_of_zero_tempo() {
+        let notes = vec![(0, tempo(0)), (0, note(60, 100)), (96, note(60, 0))];
+
+        let sequence = parse("zero-tempo", &smf(&[notes])).await.unwrap();
+        let tempo_map = TempoMap::new(&sequence.tracks, sequence.timing, 1.0);
+
+        // played at the fastest tempo instead of stopping time
+        let min_us_per_tick = *TEMPO_RANGE.start() as f64 / TICKS_PER_BEAT as f64;
+        assert_eq!(tempo_map.us_per_tick_at(0), min_us_per_tick);
+        assert_eq!(tempo_map.time_at(96), Duration::from_millis(10));
+        assert_eq!(tempo_map.tick_at(Duration::from_millis(10)), 96);
+    }
 }
@@ -0,0 +1,118 @@
+use std::path::Path;
+
+use log::trace;
+use midly::{live::LiveEvent, TrackEvent};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::midi::{convert, EventKind};
+
+/// Reads the messages of a MIDI input port as they arrive, from a raw MIDI device file like
+/// `/dev/snd/midiC1D0`, or anything else that streams MIDI bytes, like a FIFO
+pub struct MidiInput {
+    file: File,
+    buf: [u8; 64],
+    // bytes of buf not parsed yet
+    pending: std::ops::Range<usize>,
+    parser: StreamParser,
+}
+
+impl MidiInput {
+    pub async fn open(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let file = File::open(&path)
+            .await
+            .map_err(|e| format!("{}: {e}", path.as_ref().to_string_lossy()))?;
+
+        Ok(Self {
+            file,
+            buf: [0; 64],
+            pending: 0..0,
+            parser: StreamParser::default(),
+        })
+    }
+
+    /// The next message that converts to an event, None once the port is closed
+    pub async fn next(
+        &mut self,
+    ) -> Result<Option<EventKind>, Box<dyn std::error::Error + Send + Sync>> {
+        loop {
+            for i in self.pending.by_ref() {
+                let Some(message) = self.parser.push(self.buf[i]) else {
+                    continue;
+                };
+
+                trace!("midi in: {message:02x?}");
+
+                let Ok(LiveEvent::Midi { channel, message }) = LiveEvent::parse(&message) else {
+                    continue;
+                };
+
+                let event = convert(&TrackEvent {
+                    delta: 0.into(),
+                    kind: midly::TrackEventKind::Midi { channel, message },
+                });
+                if let Some(kind) = event.kind {
+                    return Ok(Some(kind));
+                }
+            }
+
+            let n = self.file.read(&mut self.buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            self.pending = 0..n;
+        }
+    }
+}
+
+// splits a byte stream into complete channel messages, following running status and skipping
+// system exclusive and system messages
+#[derive(Debug, Default)]
+struct StreamParser {
+    running_status: Option<u8>,
+    message: Vec<u8>,
+    in_sysex: bool,
+}
+
+impl StreamParser {
+    fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            // real time messages can arrive in the middle of others
+            0xf8..=0xff => None,
+            0xf0 => {
+                self.in_sysex = true;
+                self.running_status = None;
+                None
+            }
+            0xf1..=0xf7 => {
+                self.in_sysex = false;
+                self.running_status = None;
+                None
+            }
+            0x80..=0xef => {
+                self.in_sysex = false;
+                self.running_status = Some(byte);
+                self.message.clear();
+                None
+            }
+            _ => {
+                let status = self.running_status.filter(|_| !self.in_sysex)?;
+
+                self.message.push(byte);
+                let length = match status >> 4 {
+                    0xc | 0xd => 1,
+                    _ => 2,
+                };
+
+                if self.message.len() < length {
+                    return None;
+                }
+
+                let mut message = vec![status];
+                message.append(&mut self.message);
+                Some(message)
+            }
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, path::Path};
+
+use async_trait::async_trait;
+use log::{info, trace};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+};
+
+use crate::{device::Device, play::OCTAVE_8_FREQS};
+
+// the pitch bend range of synthesizers that haven't been told otherwise, in semitones
+const BEND_RANGE: f64 = 2.0;
+
+/// Sends the tones as MIDI notes to a raw MIDI output device, like `/dev/snd/midiC1D0`, to play
+/// them on a synthesizer instead of the speaker. The notes are the keys closest to the
+/// frequencies, the rest of the way is bent, which bends the whole channel along.
+pub struct MidiOutDevice {
+    file: File,
+    channel: u8,
+    // the key each sounding frequency was sent as
+    sounding: HashMap<u16, u8>,
+    // the bend that was sent last, the center if none was
+    bend: u16,
+}
+
+impl MidiOutDevice {
+    pub async fn open(
+        path: impl AsRef<Path>,
+        channel: u8,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .await
+            .map_err(|e| format!("{}: {e}", path.as_ref().to_string_lossy()))?;
+
+        info!(
+            "sending notes to {} on channel {channel}",
+            path.as_ref().to_string_lossy()
+        );
+
+        Ok(Self {
+            file,
+            channel,
+            sounding: HashMap::new(),
+            bend: 0x2000,
+        })
+    }
+
+    async fn send(
+        &mut self,
+        message: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        trace!("midi out: {message:02x?}");
+
+        self.file.write_all(message).await?;
+        self.file.flush().await?;
+
+        Ok(())
+    }
+
+    async fn release(
+        &mut self,
+        frequency: u16,
+        release_vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // tones that were never sent, like those out of the range of keys, have nothing to stop
+        let Some(key) = self.sounding.remove(&frequency) else {
+            return Ok(());
+        };
+
+        self.send(&[0x80 | self.channel, key, release_vel.min(127)])
+            .await
+    }
+}
+
+// the key closest to the frequency, numbered with c5 = 72 like key_to_frequency, and the bend
+// that makes up the rest. The frequencies are cut to whole hertz, so differences within a hertz
+// are not bent.
+fn frequency_to_key(frequency: u16) -> Option<(u8, u16)> {
+    let frequency = frequency as f64;
+    let semitones = 12.0 * (frequency / OCTAVE_8_FREQS[0]).log2() + 96.0;
+
+    let key = semitones.round();
+    if !(0.0..=127.0).contains(&key) {
+        return None;
+    }
+
+    let rounding = 12.0 * ((frequency + 1.0) / frequency).log2();
+    let off = semitones - key;
+    let bend = if off.abs() <= rounding {
+        0x2000
+    } else {
+        (0x2000 as f64 + off / BEND_RANGE * 0x2000 as f64).clamp(0.0, 0x3fff as f64) as u16
+    };
+
+    Some((key as u8, bend))
+}
+
+#[async_trait]
+impl Device for MidiOutDevice {
+    async fn tone_update(
+        &mut self,
+        frequency: u16,
+        vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if vel == 0 {
+            return self.note_off(frequency).await;
+        }
+        let Some((key, bend)) = frequency_to_key(frequency) else {
+            return Ok(());
+        };
+
+        // a tone that is sounding again is re-triggered
+        if self.sounding.contains_key(&frequency) {
+            self.release(frequency, 64).await?;
+        }
+
+        if bend != self.bend {
+            self.send(&[0xe0 | self.channel, (bend & 0x7f) as u8, (bend >> 7) as u8])
+                .await?;
+            self.bend = bend;
+        }
+
+        self.send(&[0x90 | self.channel, key, vel.min(127)]).await?;
+        self.sounding.insert(frequency, key);
+
+        Ok(())
+    }
+
+    async fn note_off(
+        &mut self,
+        frequency: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.release(frequency, 64).await
+    }
+
+    async fn note_off_with_velocity(
+        &mut self,
+        frequency: u16,
+        release_vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.release(frequency, release_vel).await
+    }
+
+    async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let frequencies = self.sounding.keys().copied().collect::<Vec<_>>();
+        for frequency in frequencies {
+            self.release(frequency, 64).await?;
+        }
+
+        // all notes off, for notes left over from before
+        self.send(&[0xb0 | self.channel, 123, 0]).await?;
+        self.send(&[0xe0 | self.channel, 0x00, 0x40]).await?;
+        self.bend = 0x2000;
+
+        Ok(())
+    }
+
+    async fn verify_id(
+        &mut self,
+    ) -> Result<Result<(), [u8; 4]>, Box<dyn std::error::Error + Send + Sync>> {
+        // a synthesizer has no ID to check
+        Ok(Ok(()))
+    }
+}
@@ -0,0 +1,86 @@
+use std::net::SocketAddr;
+
+use tokio::net::{lookup_host, UdpSocket};
+
+/// Sends an OSC message for every note to a receiver over UDP, for software that follows along
+/// with playback, like visuals or a lighting rig. Every note on and note off is sent as
+/// `/note track key velocity frequency`, with a velocity of 0 for note offs.
+pub struct OscSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl OscSink {
+    pub async fn connect(target: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let target = lookup_host(target)
+            .await
+            .map_err(|e| format!("{target}: {e}"))?
+            .next()
+            .ok_or_else(|| format!("{target}: no address found"))?;
+
+        let local: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+
+        Ok(Self {
+            socket: UdpSocket::bind(local).await?,
+            target,
+        })
+    }
+
+    pub async fn note(
+        &self,
+        track: usize,
+        key: u8,
+        vel: u8,
+        frequency: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let message = encode_message(
+            "/note",
+            &[
+                Argument::Int(track as i32),
+                Argument::Int(key.into()),
+                Argument::Int(vel.into()),
+                Argument::Float(frequency.into()),
+            ],
+        );
+
+        self.socket.send_to(&message, self.target).await?;
+        Ok(())
+    }
+}
+
+enum Argument {
+    Int(i32),
+    Float(f32),
+}
+
+// strings are terminated by at least one null byte and padded with more to a multiple of 4
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    buf.extend(std::iter::repeat_n(0, 4 - s.len() % 4));
+}
+
+fn encode_message(address: &str, arguments: &[Argument]) -> Vec<u8> {
+    let type_tags = std::iter::once(',')
+        .chain(arguments.iter().map(|argument| match argument {
+            Argument::Int(_) => 'i',
+            Argument::Float(_) => 'f',
+        }))
+        .collect::<String>();
+
+    let mut buf = Vec::new();
+    push_string(&mut buf, address);
+    push_string(&mut buf, &type_tags);
+
+    for argument in arguments {
+        match argument {
+            Argument::Int(value) => buf.extend_from_slice(&value.to_be_bytes()),
+            Argument::Float(value) => buf.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+
+    buf
+}
@@ -0,0 +1,102 @@
+use std::{collections::HashMap, time::Duration};
+
+use crate::midi::{key_name, EventKind, OctaveNaming, TempoMap, Track};
+
+struct Note {
+    key: u8,
+    start: Duration,
+    end: Duration,
+}
+
+fn collect_notes(tracks: &[Track], tempo_map: &TempoMap) -> Vec<Note> {
+    let mut notes = Vec::new();
+
+    for track in tracks {
+        // notes that have started but not ended yet, by key
+        let mut sounding: HashMap<u8, Vec<Duration>> = HashMap::new();
+        let mut last_tick = 0;
+
+        for (tick, event) in track.clone().with_ticks() {
+            last_tick = tick;
+
+            if let Some(EventKind::NoteUpdate { key, vel, .. }) = event.kind {
+                let time = tempo_map.time_at(tick);
+
+                if vel != 0 {
+                    sounding.entry(key).or_default().push(time);
+                } else if let Some(start) = sounding.get_mut(&key).and_then(|starts| starts.pop()) {
+                    notes.push(Note {
+                        key,
+                        start,
+                        end: time,
+                    });
+                }
+            }
+        }
+
+        // notes that are never released sound until the end of their track
+        let end = tempo_map.time_at(last_tick);
+        for (key, starts) in sounding {
+            notes.extend(starts.into_iter().map(|start| Note { key, start, end }));
+        }
+    }
+
+    notes
+}
+
+/// Prints the notes of the tracks as text, one row per `resolution` of time and one column per
+/// key between the lowest and highest key that is played.
+pub fn print(
+    tracks: &[Track],
+    tempo_map: &TempoMap,
+    resolution: Duration,
+    octave_naming: OctaveNaming,
+) {
+    let notes = collect_notes(tracks, tempo_map);
+
+    let (Some(lowest), Some(highest)) = (
+        notes.iter().map(|note| note.key).min(),
+        notes.iter().map(|note| note.key).max(),
+    ) else {
+        println!("no notes to show");
+        return;
+    };
+
+    let end = notes.iter().map(|note| note.end).max().unwrap_or_default();
+
+    println!(
+        "piano roll: keys {lowest} ({}) to {highest} ({}), {} ms per row, ':' marks C",
+        key_name(lowest, octave_naming),
+        key_name(highest, octave_naming),
+        resolution.as_millis()
+    );
+
+    let mut row_start = Duration::ZERO;
+    loop {
+        let row_end = row_start + resolution;
+
+        let row = (lowest..=highest)
+            .map(|key| {
+                // very short notes still get a row if they start within it
+                let active = notes.iter().any(|note| {
+                    note.key == key
+                        && note.start < row_end
+                        && (note.end > row_start || note.start >= row_start)
+                });
+
+                match (active, key % 12) {
+                    (true, _) => '#',
+                    (false, 0) => ':',
+                    (false, _) => '.',
+                }
+            })
+            .collect::<String>();
+
+        println!("{:>9.3}s {row}", row_start.as_secs_f64());
+
+        row_start = row_end;
+        if row_start >= end {
+            break;
+        }
+    }
+}
@@ -1,20 +1,61 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
 
+use futures::Stream;
+use log::{debug, info, warn};
 use tokio::{
-    sync::{broadcast, Barrier, Mutex},
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
     time::Instant,
 };
 
-use crate::{args::Speed, midi::Event, DeviceMutex};
+use crate::{
+    args::PlayOptions,
+    device::{centihertz_to_hertz, BoxedDevice, DeviceHandle, Tone},
+    freq_script::FreqScript,
+    midi::{clamp_tempo, key_name, Directive, Event, EventKind, Timing, Track, TEMPO_RANGE},
+    midi_in::MidiInput,
+    osc::OscSink,
+};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct InstrumentCount {
     pub current: usize,
     pub max: usize,
+    // when a note last started or stopped
+    pub last_change: Option<Instant>,
+}
+
+impl InstrumentCount {
+    // returns true if the note reached a new maximum
+    fn update(&mut self, vel: u8) -> bool {
+        self.last_change = Some(Instant::now());
+
+        if vel != 0 {
+            self.current += 1;
+
+            if self.current > self.max {
+                self.max = self.current;
+                return true;
+            }
+        } else {
+            self.current = self.current.saturating_sub(1);
+        }
+
+        false
+    }
 }
 
+pub const OCTAVE_8_FREQS: [f64; 12] = [
+    4186.0, 4434.0, 4699.0, 4978.0, 5274.0, 5588.0, 5920.0, 6272.0, 6645.0, 7040.0, 7459.0, 7902.0,
+];
+
 // c5 = 72
-fn key_to_frequency(key: u8) -> f64 {
+fn key_to_frequency(key: u8, octave_8_freqs: &[f64; 12]) -> f64 {
     let note = key as usize % 12;
     let octave = key as i32 / 12;
 
@@ -33,27 +74,148 @@ fn key_to_frequency(key: u8) -> f64 {
     11 B
      */
 
-    let octave_8_freqs = [
-        4186.0, 4434.0, 4699.0, 4978.0, 5274.0, 5588.0, 5920.0, 6272.0, 6645.0, 7040.0, 7459.0,
-        7902.0,
-    ];
-
     octave_8_freqs[note] / 2.0f64.powi(8 - octave)
 }
 
+fn note_frequency(key: u8, options: &PlayOptions) -> u16 {
+    (key_to_frequency(key, &options.note_table) * options.speed.pitch) as u16
+}
+
+// in hundredths of a hertz, which tell the low notes apart far better than whole hertz
+fn note_centihertz(key: u8, options: &PlayOptions) -> u32 {
+    (key_to_frequency(key, &options.note_table) * options.speed.pitch * 100.0) as u32
+}
+
+/// What is played for notes the speakers can't play, for --out-of-range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRange {
+    /// play the closest frequency that can be played
+    Clamp,
+    /// move the note by octaves until it can be played
+    Fold,
+    /// play this frequency instead, keeping the rhythm
+    Fixed(u16),
+    /// don't play the note at all
+    Drop,
+}
+
+// key -> the pitch it is retuned to by MIDI Tuning Standard SysEx, in semitones from key 0
+pub type Tuning = HashMap<u8, f64>;
+
+// the frequency of a key that may have been transposed past the MIDI keys in hundredths of a
+// hertz, with notes outside of them or of --freq-range handled as --out-of-range says. None for
+// dropped notes
+fn key_centihertz(key: i32, tuning: &Tuning, options: &PlayOptions) -> Option<u32> {
+    let key = match options.out_of_range {
+        _ if (0..=127).contains(&key) => key as u8,
+        OutOfRange::Clamp => key.clamp(0, 127) as u8,
+        // the octave of the key closest to the edge it went past
+        OutOfRange::Fold if key < 0 => key.rem_euclid(12) as u8,
+        OutOfRange::Fold => (127 - (127 - key).rem_euclid(12)) as u8,
+        OutOfRange::Fixed(frequency) => return Some(frequency as u32 * 100),
+        OutOfRange::Drop => return None,
+    };
+    let centihertz = match tuning.get(&key) {
+        // the fraction of a semitone is added on top of the note table
+        Some(pitch) => {
+            (key_to_frequency(pitch.trunc() as u8, &options.note_table)
+                * 2.0f64.powf(pitch.fract() / 12.0)
+                * options.speed.pitch
+                * 100.0) as u32
+        }
+        None => note_centihertz(key, options),
+    };
+
+    let Some((low, high)) = options.freq_range else {
+        return Some(centihertz);
+    };
+    // the range is in whole hertz, so anything within its last hertz is in it
+    let (low, high) = (low as u32 * 100, high as u32 * 100 + 99);
+
+    match options.out_of_range {
+        _ if (low..=high).contains(&centihertz) => Some(centihertz),
+        OutOfRange::Clamp => Some(centihertz.clamp(low, high)),
+        OutOfRange::Fold => {
+            let mut centihertz = centihertz.max(1);
+            while centihertz < low {
+                centihertz *= 2;
+            }
+            while centihertz > high {
+                centihertz /= 2;
+            }
+            // ranges narrower than an octave may not have the note in any octave
+            Some(centihertz.clamp(low, high))
+        }
+        OutOfRange::Fixed(frequency) => Some(frequency as u32 * 100),
+        OutOfRange::Drop => None,
+    }
+}
+
+/// The channel General MIDI plays percussion on, channel 10 counting from 1
+pub const DRUM_CHANNEL: u8 = 9;
+
+/// The frequency a key of a track is played at in hundredths of a hertz, with the frequencies of
+/// the drum map for notes on the percussion channel in --drum-mode. None for notes that aren't
+/// played at all
+pub fn played_centihertz(
+    key: u8,
+    channel: u8,
+    transpose: i32,
+    tuning: &Tuning,
+    options: &PlayOptions,
+) -> Option<u32> {
+    match &options.drum_map {
+        Some(drum_map) if channel == DRUM_CHANNEL => {
+            drum_map.get(&key).map(|frequency| *frequency as u32 * 100)
+        }
+        _ => key_centihertz(key as i32 + transpose, tuning, options),
+    }
+}
+
+/// Plays an ascending C major scale straight on the device and resets it, to check that the
+/// hardware works without needing a file
+pub async fn play_test_scale(
+    device: &mut BoxedDevice,
+    options: &PlayOptions,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    const SCALE: [u8; 8] = [72, 74, 76, 77, 79, 81, 83, 84];
+
+    for key in SCALE {
+        let frequency = note_frequency(key, options);
+        info!("testing {frequency} Hz");
+
+        device.tone_update(frequency, 100).await?;
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        device.note_off(frequency).await?;
+    }
+
+    device.reset().await
+}
+
+// returns true if playback was stopped while sleeping. The wakeup time is kept as an offset from
+// the start of playback, which all tracks share, so that they all schedule against one clock.
 async fn sleep_until(
-    wakeup_time: &mut Instant,
+    start: Instant,
+    wakeup_offset: &mut Duration,
     mut remaining_ticks: u32,
     tick_us: &mut u32,
-    tick_update_rx: &mut broadcast::Receiver<u32>,
-) {
+    tick_update_rx: &mut mpsc::UnboundedReceiver<u32>,
+    stop: &mut watch::Receiver<bool>,
+) -> bool {
     loop {
+        if *stop.borrow() {
+            break true;
+        }
+
         let start_wait = Instant::now();
         tokio::select! {
-            _ = tokio::time::sleep_until(*wakeup_time) => {
-                break;
+            _ = tokio::time::sleep_until(start + *wakeup_offset) => {
+                break false;
             },
-        Ok(new_tick_us) = tick_update_rx.recv() => {
+        Ok(()) = stop.changed() => {
+            continue;
+        }
+        Some(new_tick_us) = tick_update_rx.recv() => {
             let now = Instant::now();
             let elapsed_time = now - start_wait;
             let elapsed_old_ticks = (elapsed_time.as_secs_f64() * 1_000_000.0) / *tick_us as f64;
@@ -62,9 +224,9 @@ async fn sleep_until(
             remaining_ticks = remaining_ticks.saturating_sub(completed_old_ticks);
 
             if new_tick_us > *tick_us {
-                *wakeup_time += Duration::from_micros((remaining_ticks * (new_tick_us - *tick_us)).into());
+                *wakeup_offset += Duration::from_micros(remaining_ticks as u64 * (new_tick_us - *tick_us) as u64);
             } else {
-                *wakeup_time -= Duration::from_micros((remaining_ticks * (*tick_us - new_tick_us)).into());
+                *wakeup_offset = wakeup_offset.saturating_sub(Duration::from_micros(remaining_ticks as u64 * (*tick_us - new_tick_us) as u64));
             }
 
             *tick_us = new_tick_us;
@@ -73,105 +235,2031 @@ async fn sleep_until(
     }
 }
 
-async fn handle_note_update(
-    device: Arc<DeviceMutex>,
-    key: u8,
-    vel: u8,
-    instrument_count: Arc<Mutex<InstrumentCount>>,
-    pitch: f64,
+// scales the velocity linearly from almost nothing at the start of playback to the full
+// velocity at the end of the soft start window
+fn soft_start_velocity(vel: u8, elapsed: Duration, window: Duration) -> u8 {
+    if vel == 0 || elapsed >= window {
+        return vel;
+    }
+
+    let scaled = vel as f64 * elapsed.as_secs_f64() / window.as_secs_f64();
+    (scaled.round() as u8).max(1)
+}
+
+// maps the velocity to a percentage of the period, up to 50% at full velocity, so that buzzers
+// get quieter with lower velocities
+fn duty_cycle(vel: u8, options: &PlayOptions) -> Option<u8> {
+    let exponent = options.duty_map?;
+    if vel == 0 {
+        return None;
+    }
+
+    let duty = 50.0 * (vel as f64 / 127.0).powf(exponent);
+    Some((duty.round() as u8).clamp(1, 50))
+}
+
+/// A controller with 14 bits of resolution, made up of an MSB controller and its LSB partner 32
+/// numbers above it
+#[derive(Debug, Clone, Copy)]
+struct HighResController {
+    msb: u8,
+    lsb: u8,
+}
+
+impl HighResController {
+    fn new(msb: u8) -> Self {
+        Self { msb, lsb: 0 }
+    }
+
+    // full scale is reached at an MSB of 127, whatever the LSB is
+    fn scale(&self) -> f64 {
+        let value = ((self.msb as u16) << 7) | self.lsb as u16;
+        (value as f64 / (127 << 7) as f64).min(1.0)
+    }
+}
+
+/// The controllers of a track that affect how loud its notes are played
+#[derive(Debug, Clone, Copy)]
+struct Controllers {
+    volume: HighResController,
+    expression: HighResController,
+    // set by the `@volume` directive
+    directive_volume: f64,
+    // set by --track-volume
+    track_volume: f64,
+}
+
+impl Default for Controllers {
+    fn default() -> Self {
+        Self {
+            volume: HighResController::new(127),
+            expression: HighResController::new(127),
+            directive_volume: 1.0,
+            track_volume: 1.0,
+        }
+    }
+}
+
+impl Controllers {
+    fn update(&mut self, controller: u8, value: u8) {
+        match controller {
+            // a new MSB invalidates the LSB that was sent along with the previous one
+            7 => self.volume = HighResController::new(value),
+            11 => self.expression = HighResController::new(value),
+            39 => self.volume.lsb = value,
+            43 => self.expression.lsb = value,
+            _ => (),
+        }
+    }
+
+    fn scale_velocity(&self, vel: u8) -> u8 {
+        if vel == 0 {
+            return 0;
+        }
+
+        let scaled = vel as f64
+            * self.volume.scale()
+            * self.expression.scale()
+            * self.directive_volume
+            * self.track_volume;
+        (scaled.round().min(127.0) as u8).max(1)
+    }
+}
+
+// the notes of a track in the order they started, as (key, tone), for the arpeggiator or the
+// glider
+type HeldTones = Vec<(u8, Tone)>;
+
+/// Sounds one of the notes it is given at a time, moving on to the next one every `interval`
+/// while there are several. Ends once the sender is dropped.
+async fn run_arpeggiator(
+    device: DeviceHandle,
+    interval: Duration,
+    mut notes_rx: watch::Receiver<HeldTones>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut sounding: Option<Tone> = None;
+    let mut position = 0;
+    let mut next_step = Instant::now() + interval;
+
+    loop {
+        let notes = notes_rx.borrow().clone();
+        let now = Instant::now();
+
+        if notes.len() <= 1 {
+            // start over with the first note once a chord starts again
+            position = 0;
+            next_step = now + interval;
+        } else if now >= next_step {
+            position += 1;
+            next_step = now + interval;
+        }
+
+        let target = match notes.len() {
+            0 => None,
+            len => notes.get(position % len).map(|(_, tone)| *tone),
+        };
+
+        if target != sounding {
+            if let Some(tone) = sounding {
+                device.tone_update(now, tone.stopped()).await?;
+            }
+            if let Some(tone) = target {
+                device.tone_update(now, tone).await?;
+            }
+            sounding = target;
+        }
+
+        tokio::select! {
+            changed = notes_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(next_step), if notes.len() > 1 => (),
+        }
+    }
+
+    if let Some(tone) = sounding {
+        device.tone_update(Instant::now(), tone.stopped()).await?;
+    }
+
+    Ok(())
+}
+
+// a note that starts this soon after the last one ended still slides from it, as the note off
+// and the note on of the same tick can reach the glider apart
+const LEGATO_WINDOW: Duration = Duration::from_millis(5);
+
+// the frequency `progress` of the way from `from` to `to`, evenly in pitch, in hundredths of a
+// hertz
+fn glide_centihertz(from: u32, to: u32, progress: f64) -> u32 {
+    (from as f64 * (to as f64 / from as f64).powf(progress)).round() as u32
+}
+
+/// Sounds the last note it is given, sliding to the pitch of every new note from the one that
+/// is sounding or has just ended over `duration`, in steps of `step`. Ends once the sender is
+/// dropped.
+async fn run_glider(
+    device: DeviceHandle,
+    duration: Duration,
+    step: Duration,
+    mut notes_rx: watch::Receiver<HeldTones>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut device_lock = device.lock().await;
-    device_lock
-        .tone_update((key_to_frequency(key) * pitch) as u16, vel)
-        .await?;
+    let mut sounding: Option<Tone> = None;
+    // the key of the note slid to or sounding and the last tone that ended, with when
+    let mut key: Option<u8> = None;
+    let mut ended: Option<(Instant, Tone)> = None;
+    // the frequency the slide underway started from and when it did
+    let mut slide: Option<(u32, Instant)> = None;
+
+    loop {
+        let target = notes_rx.borrow().last().copied();
+        let now = Instant::now();
+
+        let tone = match target {
+            None => {
+                key = None;
+                slide = None;
+                None
+            }
+            Some((target_key, target)) => {
+                if key != Some(target_key) {
+                    key = Some(target_key);
+                    let from = sounding.or_else(|| {
+                        ended
+                            .filter(|(at, _)| now.duration_since(*at) <= LEGATO_WINDOW)
+                            .map(|(_, tone)| tone)
+                    });
+                    slide = from.map(|from| {
+                        let centihertz = from
+                            .centihertz
+                            .unwrap_or(from.frequency as u32 * 100)
+                            .max(1);
+                        (centihertz, now)
+                    });
+                }
 
-    drop(device_lock);
+                match (slide, target.centihertz) {
+                    (Some((from, start)), Some(to)) => {
+                        let progress =
+                            now.duration_since(start).as_secs_f64() / duration.as_secs_f64();
+                        if progress >= 1.0 {
+                            slide = None;
+                            Some(target)
+                        } else {
+                            let centihertz = glide_centihertz(from, to, progress);
+                            Some(Tone {
+                                frequency: centihertz_to_hertz(centihertz),
+                                centihertz: Some(centihertz),
+                                ..target
+                            })
+                        }
+                    }
+                    _ => {
+                        slide = None;
+                        Some(target)
+                    }
+                }
+            }
+        };
 
-    let mut instrument_count_lock = instrument_count.lock().await;
-    if vel != 0 {
-        instrument_count_lock.current += 1;
+        if tone != sounding {
+            if let Some(tone) = sounding {
+                device.tone_update(now, tone.stopped()).await?;
+                ended = Some((now, tone));
+            }
+            if let Some(tone) = tone {
+                device.tone_update(now, tone).await?;
+            }
+            sounding = tone;
+        }
 
-        if instrument_count_lock.current > instrument_count_lock.max {
-            instrument_count_lock.max = instrument_count_lock.current;
-            println!("new maximum notes: {}", instrument_count_lock.max);
+        tokio::select! {
+            changed = notes_rx.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(now + step), if slide.is_some() => (),
         }
-    } else {
-        instrument_count_lock.current -= 1;
     }
-    drop(instrument_count_lock);
+
+    if let Some(tone) = sounding {
+        device.tone_update(Instant::now(), tone.stopped()).await?;
+    }
 
     Ok(())
 }
 
+/// The notes a track has sounding on the device, along with its note counts
+struct TrackNotes {
+    // the index of the track, 0 for live input
+    track_index: usize,
+    device: DeviceHandle,
+    // also receives the notes if --osc is given
+    osc: Option<Arc<OscSink>>,
+    instrument_count: Arc<Mutex<InstrumentCount>>,
+    options: Arc<PlayOptions>,
+    start: Instant,
+    // key -> velocity of the notes this track is sounding
+    held: HashMap<u8, u8>,
+    count: InstrumentCount,
+    controllers: Controllers,
+    // hands the notes to the arpeggiator or the glider instead of the device when arpeggiating
+    // or gliding
+    held_tones: Option<watch::Sender<HeldTones>>,
+    // key -> the channel of the key's last note. Notes on the percussion channel are played at
+    // the frequencies of the drum map instead of their pitch, and aren't glided
+    channels: HashMap<u8, u8>,
+    // semitones the keys are transposed by, from --transpose-each-loop
+    transpose: i32,
+    // keys whose current note was left out for --max-voices or cut short for
+    // --max-note-duration, so that its note off is skipped
+    dropped_keys: HashSet<u8>,
+    // when the held notes started, for --max-note-duration
+    started: HashMap<u8, Instant>,
+    // key -> device voice from --channel-voice, for the channel of the key's last note
+    voices: HashMap<u8, u8>,
+    tuning: Tuning,
+    // key -> the frequency the held notes were started at, in hundredths of a hertz
+    frequencies: HashMap<u8, u32>,
+}
+
+impl TrackNotes {
+    // true for the notes left out for --max-voices, and for the note offs of those and of the
+    // notes cut short for --max-note-duration
+    fn is_dropped(&mut self, track_index: usize, tick: u64, key: u8, vel: u8) -> bool {
+        if vel == 0 {
+            return self.dropped_keys.remove(&key);
+        }
+
+        if self
+            .options
+            .culled_notes
+            .contains(&(track_index, tick, key))
+        {
+            self.dropped_keys.insert(key);
+            true
+        } else {
+            self.dropped_keys.remove(&key);
+            false
+        }
+    }
+
+    // remembers the channel a note is started on and its voice, so that its note off goes
+    // to the same voice and it is sounded again the same way
+    fn assign_voice(&mut self, key: u8, channel: u8, vel: u8) {
+        if vel == 0 {
+            return;
+        }
+
+        self.channels.insert(key, channel);
+        match self.options.channel_voice(channel) {
+            Some(voice) => self.voices.insert(key, voice),
+            None => self.voices.remove(&key),
+        };
+    }
+
+    // when the first held note reaches --max-note-duration
+    fn next_expiry(&self) -> Option<Instant> {
+        let max_duration = self.options.max_note_duration?;
+        self.started
+            .values()
+            .min()
+            .map(|started| *started + max_duration)
+    }
+
+    // releases the notes that have reached --max-note-duration by `now`, returning their keys
+    async fn release_expired(
+        &mut self,
+        now: Instant,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let Some(max_duration) = self.options.max_note_duration else {
+            return Ok(Vec::new());
+        };
+
+        let expired = self
+            .started
+            .iter()
+            .filter(|(_, started)| **started + max_duration <= now)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in expired.iter().copied() {
+            self.handle_note_update(now, key, 0, None).await?;
+            self.dropped_keys.insert(key);
+        }
+
+        Ok(expired)
+    }
+
+    // in hundredths of a hertz. None for drum keys without a frequency and out of range notes
+    // that are dropped, which aren't played at all
+    fn centihertz(&self, key: u8) -> Option<u32> {
+        let channel = self.channels.get(&key).copied().unwrap_or_default();
+        played_centihertz(key, channel, self.transpose, &self.tuning, &self.options)
+    }
+
+    fn is_drum(&self, key: u8) -> bool {
+        self.channels.get(&key) == Some(&DRUM_CHANNEL)
+    }
+
+    // whether a note goes to the arpeggiator or the glider instead of the device, drums are
+    // arpeggiated but not glided
+    fn to_tone_task(&self, key: u8) -> bool {
+        self.held_tones.is_some() && (self.options.arpeggiate.is_some() || !self.is_drum(key))
+    }
+
+    // the velocity a note is sent with, 0 stays a note off. The steps are applied in order:
+    // 1. inversion for --invert-velocity
+    // 2. the volume and expression controllers, the @volume directive and --track-volume
+    // 3. the ramp of --soft-start
+    fn output_velocity(&self, timestamp: Instant, vel: u8) -> u8 {
+        if vel == 0 {
+            return 0;
+        }
+
+        let vel = if self.options.invert_velocity {
+            128 - vel.min(127)
+        } else {
+            vel
+        };
+
+        let vel = self.controllers.scale_velocity(vel);
+
+        match self.options.soft_start {
+            Some(window) => {
+                soft_start_velocity(vel, timestamp.saturating_duration_since(self.start), window)
+            }
+            None => vel,
+        }
+    }
+
+    // logs how far the frequency of a note is from the key in equal temperament, for
+    // --show-cents
+    fn log_cents(&self, key: u8, centihertz: u32) {
+        let frequency = centihertz as f64 / 100.0;
+        let Some(played_key) = u8::try_from(key as i32 + self.transpose)
+            .ok()
+            .filter(|key| *key <= 127)
+        else {
+            info!("track {}: key {key} at {frequency:.2} Hz", self.track_index);
+            return;
+        };
+
+        let reference = key_to_frequency(played_key, &OCTAVE_8_FREQS);
+        let cents = 1200.0 * (frequency / reference).log2();
+        info!(
+            "track {}: key {played_key} ({}) at {frequency:.2} Hz, {cents:+.1} cents from {reference:.1} Hz",
+            self.track_index,
+            key_name(played_key, self.options.octave_naming)
+        );
+    }
+
+    async fn handle_note_update(
+        &mut self,
+        timestamp: Instant,
+        key: u8,
+        vel: u8,
+        release_vel: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // a note off goes to the frequency the note was started at, which a tuning may have
+        // changed since
+        let centihertz = match vel {
+            0 => self
+                .frequencies
+                .remove(&key)
+                .or_else(|| self.centihertz(key)),
+            _ => self.centihertz(key),
+        };
+        let Some(centihertz) = centihertz else {
+            return Ok(());
+        };
+        if vel != 0 {
+            self.frequencies.insert(key, centihertz);
+
+            if self.options.show_cents && !self.is_drum(key) {
+                self.log_cents(key, centihertz);
+            }
+        }
+        let frequency = centihertz_to_hertz(centihertz);
+
+        let out_vel = self.output_velocity(timestamp, vel);
+
+        if let Some(osc) = &self.osc {
+            osc.note(self.track_index, key, out_vel, frequency).await?;
+        }
+
+        let tone = Tone {
+            frequency,
+            vel: out_vel,
+            duty: duty_cycle(out_vel, &self.options),
+            voice: self.voices.get(&key).copied(),
+            release_vel: release_vel.filter(|_| self.options.release_velocity),
+            centihertz: Some(centihertz),
+        };
+        match &self.held_tones {
+            Some(held_tones) if self.to_tone_task(key) => held_tones.send_modify(|notes| {
+                notes.retain(|(held_key, _)| *held_key != key);
+                if out_vel != 0 {
+                    notes.push((key, tone));
+                }
+            }),
+            _ => self.device.tone_update(timestamp, tone).await?,
+        }
+
+        if vel != 0 {
+            self.held.insert(key, vel);
+            self.started.insert(key, timestamp);
+        } else {
+            self.held.remove(&key);
+            self.started.remove(&key);
+        }
+
+        self.count.update(vel);
+
+        let mut instrument_count_lock = self.instrument_count.lock().await;
+        if instrument_count_lock.update(vel) {
+            info!("new maximum notes: {}", instrument_count_lock.max);
+        }
+        drop(instrument_count_lock);
+
+        Ok(())
+    }
+
+    async fn handle_aftertouch(
+        &self,
+        timestamp: Instant,
+        key: Option<u8>,
+        value: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // no pressure still means a sounding note, it must not turn into a note off
+        let vel = self.controllers.scale_velocity(value.max(1));
+        let duty = duty_cycle(vel, &self.options);
+
+        if let Some(held_tones) = &self.held_tones {
+            held_tones.send_modify(|notes| {
+                notes
+                    .iter_mut()
+                    .filter(|(held_key, _)| key.is_none_or(|key| key == *held_key))
+                    .for_each(|(_, tone)| {
+                        tone.vel = vel;
+                        tone.duty = duty;
+                    })
+            });
+        }
+
+        for (centihertz, voice) in self
+            .held
+            .keys()
+            .filter(|held_key| key.is_none_or(|key| key == **held_key))
+            .filter(|held_key| !self.to_tone_task(**held_key))
+            .filter_map(|held_key| {
+                Some((
+                    *self.frequencies.get(held_key)?,
+                    self.voices.get(held_key).copied(),
+                ))
+            })
+        {
+            let tone = Tone {
+                frequency: centihertz_to_hertz(centihertz),
+                vel,
+                duty,
+                voice,
+                release_vel: None,
+                centihertz: Some(centihertz),
+            };
+            self.device.tone_update(timestamp, tone).await?;
+        }
+
+        Ok(())
+    }
+
+    // sends note offs for every held note
+    async fn release_held_notes(
+        &mut self,
+        timestamp: Instant,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let keys = self.held.keys().copied().collect::<Vec<_>>();
+        for key in keys {
+            self.handle_note_update(timestamp, key, 0, None).await?;
+        }
+
+        Ok(())
+    }
+}
+
+// sends a new tick length to playback. Sending only fails once playback has ended, which then
+// has no use for it anymore, so that only returns false instead of failing the run
+fn send_tick(tick_update_tx: &mpsc::UnboundedSender<u32>, tick_us: u32) -> bool {
+    if tick_update_tx.send(tick_us).is_err() {
+        debug!("playback has ended, dropping the tick update to {tick_us} µs");
+        return false;
+    }
+
+    true
+}
+
 async fn handle_tempo_update(
     new_us_per_beat: u32,
     ticks_per_beat: u32,
     tempo: f64,
-    tick_update_tx: &broadcast::Sender<u32>,
+    tick_update_tx: &mpsc::UnboundedSender<u32>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let us_per_tick = new_us_per_beat as f64 / (ticks_per_beat as f64);
     let us_per_tick_tempo_adjusted = us_per_tick / tempo;
 
-    tick_update_tx.send(us_per_tick_tempo_adjusted.round() as u32)?;
+    if !send_tick(tick_update_tx, us_per_tick_tempo_adjusted.round() as u32) {
+        return Ok(());
+    }
 
-    println!("tick is now {us_per_tick_tempo_adjusted} µs, adjusted from {us_per_tick} µs");
+    debug!("tick is now {us_per_tick_tempo_adjusted} µs, adjusted from {us_per_tick} µs");
 
     Ok(())
 }
 
-pub async fn play_track<I: IntoIterator<Item = Event>>(
-    track: I,
-    timing: crate::midi::Timing,
-    device: Arc<DeviceMutex>,
-    instrument_count: Arc<Mutex<InstrumentCount>>,
-    speed: Speed,
-    start_barrier: Arc<Barrier>,
-    tick_update_tx: broadcast::Sender<u32>,
+// how often the tick length is updated while ramping to a new tempo
+const TEMPO_RAMP_STEP: Duration = Duration::from_millis(10);
+
+/// Moves the tick length linearly from `from_us` to the one of the new tempo over `duration`,
+/// for --smooth-tempo
+async fn ramp_tempo(
+    from_us: u32,
+    new_us_per_beat: u32,
+    ticks_per_beat: u32,
+    tempo: f64,
+    duration: Duration,
+    tick_update_tx: mpsc::UnboundedSender<u32>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let to_us = new_us_per_beat as f64 / ticks_per_beat as f64 / tempo;
+    let steps = (duration.as_secs_f64() / TEMPO_RAMP_STEP.as_secs_f64())
+        .ceil()
+        .max(1.0) as u32;
+
+    let start = Instant::now();
+    for step in 1..=steps {
+        tokio::time::sleep_until(start + TEMPO_RAMP_STEP * (step - 1)).await;
+
+        let progress = step as f64 / steps as f64;
+        let tick_us = from_us as f64 + (to_us - from_us as f64) * progress;
+        if !send_tick(&tick_update_tx, tick_us.round() as u32) {
+            return Ok(());
+        }
+    }
+
+    debug!("tick is now {to_us} µs, ramped from {from_us} µs");
+
+    Ok(())
+}
+
+/// A section of the file that is jumped back into once its end is reached
+#[derive(Debug, Clone, Copy)]
+pub struct RepeatSection {
+    pub start_tick: u64,
+    pub end_tick: u64,
+    // the tick length in effect at the start of the section, which is restored on every jump
+    pub start_tick_us: u32,
+    // how many times to jump back, forever if None
+    pub count: Option<u32>,
+    // semitones added to the transposition on every jump back
+    pub transpose: i8,
+}
+
+// the moment playback is scheduled from, delayed by --honor-smpte-offset and set back by
+// --latency-offset so that everything is played that much early
+pub fn playback_start(smpte_offset: Duration, latency_offset: Duration) -> Instant {
+    let start = Instant::now() + smpte_offset;
+    start.checked_sub(latency_offset).unwrap_or(start)
+}
+
+/// Sends the tick lengths of the schedule to the scheduler once their time has come, until it
+/// is aborted along with playback
+async fn drive_tempo(
+    schedule: Vec<(Duration, u32)>,
+    start: Instant,
+    tick_update_tx: mpsc::UnboundedSender<u32>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    start_barrier.wait().await;
-
-    let ticks_per_beat = timing.ticks_per_beat;
-    let mut tick_us = timing.tick.as_micros() as u32;
-
-    let mut tick_update_rx = tick_update_tx.subscribe();
-
-    let mut next_time = Instant::now();
-
-    for track_event in track {
-        next_time += Duration::from_micros((track_event.delta * tick_us).into());
-
-        sleep_until(
-            &mut next_time,
-            track_event.delta,
-            &mut tick_us,
-            &mut tick_update_rx,
-        )
-        .await;
-
-        if let Some(e) = track_event.kind {
-            match e {
-                crate::midi::EventKind::NoteUpdate { key, vel } => {
-                    handle_note_update(
-                        device.clone(),
-                        key,
-                        vel,
-                        instrument_count.clone(),
-                        speed.pitch,
-                    )
-                    .await?;
-                }
-                crate::midi::EventKind::TempoUpdate(new_us_per_beat) => {
-                    handle_tempo_update(
-                        new_us_per_beat,
-                        ticks_per_beat,
-                        speed.tempo,
-                        &tick_update_tx,
-                    )
-                    .await?
+    // the first tick length is already in effect from the start
+    for (time, tick_us) in schedule.into_iter().skip(1) {
+        tokio::time::sleep_until(start + time).await;
+
+        if !send_tick(&tick_update_tx, tick_us) {
+            return Ok(());
+        }
+        debug!("tick is now {tick_us} µs from the tempo file");
+    }
+
+    Ok(())
+}
+
+type ToneTaskHandle = JoinHandle<Result<(), Box<dyn std::error::Error + Send + Sync>>>;
+
+// starts the arpeggiator of a track if --arpeggiate is given, or its glider if --glide is
+fn spawn_tone_task(
+    device: &DeviceHandle,
+    options: &PlayOptions,
+) -> (Option<watch::Sender<HeldTones>>, Option<ToneTaskHandle>) {
+    match (options.arpeggiate, options.glide) {
+        (Some(interval), _) => {
+            let (notes_tx, notes_rx) = watch::channel(Vec::new());
+            let arpeggiator = tokio::spawn(run_arpeggiator(device.clone(), interval, notes_rx));
+            (Some(notes_tx), Some(arpeggiator))
+        }
+        (None, Some((duration, step))) => {
+            let (notes_tx, notes_rx) = watch::channel(Vec::new());
+            let glider = tokio::spawn(run_glider(device.clone(), duration, step, notes_rx));
+            (Some(notes_tx), Some(glider))
+        }
+        _ => (None, None),
+    }
+}
+
+/// A position to jump playback to for --scrub
+#[derive(Debug, Clone, Copy)]
+pub struct Seek {
+    pub tick: u64,
+    // the tick length in effect at the position
+    pub tick_us: u32,
+}
+
+// the next position to jump to, never ready without --scrub or once its input has ended
+async fn next_seek(seek: &mut Option<mpsc::UnboundedReceiver<Seek>>) -> Option<Seek> {
+    match seek {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// what waiting for the next event ended with
+enum Wake {
+    Due,
+    Stopped,
+    Seek(Seek),
+    // the overlap of a delayed note that stops playback with --error-polyphony
+    Failed(String),
+}
+
+/// What a playback of a sequence needs besides its tracks
+pub struct PlayContext {
+    pub timing: Timing,
+    pub device: DeviceHandle,
+    pub instrument_count: Arc<Mutex<InstrumentCount>>,
+    pub options: Arc<PlayOptions>,
+    pub repeat: Option<RepeatSection>,
+    // the tick lengths of a tempo file and when they take effect, instead of the tempo updates
+    // of the tracks
+    pub tempo_schedule: Option<Vec<(Duration, u32)>>,
+    pub osc: Option<Arc<OscSink>>,
+    // the moment playback starts, the same for sequences played along
+    pub start: Instant,
+    pub stop: watch::Receiver<bool>,
+    // positions to jump to as they arrive, for --scrub
+    pub seek: Option<mpsc::UnboundedReceiver<Seek>>,
+    // receives the events of the tracks that are heard as they are played, instead of the
+    // device, for --print-events
+    pub events: Option<mpsc::UnboundedSender<(usize, EventKind)>>,
+}
+
+/// A track of the sequence being played, along with its next event
+struct TrackPlayer {
+    index: usize,
+    track: Track,
+    // the rest of the track after `next`
+    events: Track,
+    next: Option<(u64, Event)>,
+    // the tick of `next`, or of the last event once the track has ended
+    tick: u64,
+    // how much later than the sequence the track is played, for --track-offset
+    delay: Duration,
+    // the events played through with a delay that is still to pass, with when they are due and
+    // their tick
+    delayed: VecDeque<(Instant, u64, Option<EventKind>)>,
+    // the events are yielded to it instead of played, see `PlayContext::events`
+    stream: Option<mpsc::UnboundedSender<(usize, EventKind)>>,
+    audible: bool,
+    notes: TrackNotes,
+    tone_task: Option<ToneTaskHandle>,
+}
+
+impl TrackPlayer {
+    fn new(track: Track, context: &PlayContext, start: Instant) -> Self {
+        let options = &context.options;
+        let device = context.device.for_track(track.index);
+        let (held_tones, tone_task) = spawn_tone_task(&device, options);
+
+        let notes = TrackNotes {
+            track_index: track.index,
+            device,
+            osc: context.osc.clone(),
+            instrument_count: context.instrument_count.clone(),
+            options: options.clone(),
+            start,
+            held: HashMap::new(),
+            count: InstrumentCount::default(),
+            controllers: Controllers {
+                track_volume: options.track_volume(track.index),
+                ..Controllers::default()
+            },
+            held_tones,
+            channels: HashMap::new(),
+            transpose: 0,
+            dropped_keys: HashSet::new(),
+            started: HashMap::new(),
+            voices: HashMap::new(),
+            tuning: Tuning::new(),
+            frequencies: HashMap::new(),
+        };
+
+        let mut player = Self {
+            index: track.index,
+            events: track.clone(),
+            audible: options.is_audible(track.index),
+            delay: options.track_delay(track.index),
+            track,
+            next: None,
+            tick: 0,
+            delayed: VecDeque::new(),
+            stream: context.events.clone(),
+            notes,
+            tone_task,
+        };
+        player.advance();
+        player
+    }
+
+    fn next_tick(&self) -> Option<u64> {
+        self.next.as_ref().map(|(tick, _)| *tick)
+    }
+
+    fn advance(&mut self) {
+        self.next = self.events.next().map(|event| {
+            self.tick += event.delta as u64;
+            (self.tick, event)
+        });
+    }
+
+    // starts the track over from `start_tick`, keeping track of what is held there. The events
+    // at `start_tick` are taken as well, so that a note ending there isn't sounded again right
+    // before its note off. Delayed events still to be played are dropped
+    fn skip_to(&mut self, start_tick: u64) {
+        self.events = self.track.clone();
+        self.tick = 0;
+        self.delayed.clear();
+        self.advance();
+
+        while let Some((tick, event)) = self.next.take_if(|(tick, _)| *tick <= start_tick) {
+            match event
+                .kind
+                .filter(|kind| self.notes.options.is_enabled(kind))
+            {
+                Some(EventKind::NoteUpdate {
+                    channel, key, vel, ..
+                }) if self.audible && !self.notes.is_dropped(self.index, tick, key, vel) => {
+                    self.notes.assign_voice(key, channel, vel);
+                    if vel != 0 {
+                        self.notes.held.insert(key, vel);
+                    } else {
+                        self.notes.held.remove(&key);
+                    }
+                }
+                Some(EventKind::Controller { controller, value }) => {
+                    self.notes.controllers.update(controller, value);
+                }
+                Some(EventKind::Directive(Directive::Volume(volume))) => {
+                    self.notes.controllers.directive_volume = volume;
                 }
                 _ => (),
             }
+
+            self.advance();
         }
     }
-    Ok(())
+}
+
+// plays what an event of a track sounds like at `timestamp`, then releases what the track holds
+// if that was its last event. Returns the overlap that stops playback with --error-polyphony
+async fn play_event(
+    players: &mut [TrackPlayer],
+    slot: usize,
+    tick: u64,
+    kind: Option<EventKind>,
+    timestamp: Instant,
+    release_at_end: bool,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    if yield_event(&players[slot], kind.as_ref())? {
+        return Ok(None);
+    }
+
+    // a tuning applies to every track, as it would on a synthesizer. Notes that are sounding
+    // keep their frequency
+    if let Some(EventKind::Tuning(changes)) = &kind {
+        for player in players.iter_mut() {
+            player.notes.tuning.extend(changes.iter().copied());
+        }
+    }
+
+    let player = &mut players[slot];
+    let track_index = player.index;
+    let options = player.notes.options.clone();
+    let notes = &mut player.notes;
+
+    match kind {
+        Some(EventKind::NoteUpdate {
+            channel,
+            key,
+            vel,
+            release_vel,
+        }) if player.audible && !notes.is_dropped(track_index, tick, key, vel) => {
+            if vel != 0
+                && !notes.held.is_empty()
+                && (options.warn_polyphony || options.error_polyphony)
+            {
+                let message = format!(
+                    "track {track_index}: key {key} started while keys {:?} are sounding",
+                    notes.held.keys()
+                );
+                if options.error_polyphony {
+                    return Ok(Some(message));
+                }
+                warn!("{message}");
+            }
+
+            notes.assign_voice(key, channel, vel);
+            notes
+                .handle_note_update(timestamp, key, vel, release_vel)
+                .await?;
+        }
+        Some(EventKind::Aftertouch { key, value }) if options.aftertouch => {
+            notes.handle_aftertouch(timestamp, key, value).await?;
+        }
+        Some(EventKind::Controller { controller, value }) => {
+            notes.controllers.update(controller, value);
+        }
+        Some(EventKind::Directive(Directive::Volume(volume))) => {
+            notes.controllers.directive_volume = volume;
+        }
+        Some(EventKind::Directive(Directive::Unknown(text))) => {
+            debug!("track {track_index}: ignoring unknown directive `@{text}`")
+        }
+        _ => (),
+    }
+
+    // files with missing note offs would otherwise leave their last notes sounding, unless
+    // they are meant to be held until playback is stopped. Tracks that are jumped back into
+    // release their notes at the end of the section instead
+    if player.next.is_none()
+        && player.delayed.is_empty()
+        && release_at_end
+        && !player.notes.held.is_empty()
+        && !options.hold_end
+    {
+        warn!(
+            "track {track_index}: ended with keys {:?} still sounding, releasing them",
+            player.notes.held.keys()
+        );
+        player.notes.release_held_notes(timestamp).await?;
+    }
+
+    Ok(None)
+}
+
+// hands the event to the stream instead of playing it when there is one, returning whether it
+// did. Events of tracks that aren't heard are left out
+fn yield_event(
+    player: &TrackPlayer,
+    kind: Option<&EventKind>,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    let Some(stream) = &player.stream else {
+        return Ok(false);
+    };
+
+    if let (Some(kind), true) = (kind, player.audible) {
+        stream
+            .send((player.index, kind.clone()))
+            .map_err(|_| "the events are no longer read")?;
+    }
+
+    Ok(true)
+}
+
+// when the first of the events of the delayed tracks is due
+fn next_delayed(players: &[TrackPlayer]) -> Option<Instant> {
+    players
+        .iter()
+        .filter_map(|player| player.delayed.front().map(|(due, _, _)| *due))
+        .min()
+}
+
+// plays the events of the delayed tracks that are due by `now`, the earliest first and those
+// due at the same time in track order
+async fn play_delayed(
+    players: &mut [TrackPlayer],
+    now: Instant,
+    release_at_end: bool,
+) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    while let Some(slot) = (0..players.len())
+        .filter_map(|slot| Some((players[slot].delayed.front()?.0, slot)))
+        .filter(|(due, _)| *due <= now)
+        .min()
+        .map(|(_, slot)| slot)
+    {
+        let Some((due, tick, kind)) = players[slot].delayed.pop_front() else {
+            break;
+        };
+
+        let failure = play_event(players, slot, tick, kind, due, release_at_end).await?;
+        if failure.is_some() {
+            return Ok(failure);
+        }
+    }
+
+    Ok(None)
+}
+
+// the tick and slot of every track's next event, the earliest first and those of the same tick
+// in track order
+type EventQueue = BinaryHeap<Reverse<(u64, usize)>>;
+
+fn enqueue(queue: &mut EventQueue, slot: usize, player: &TrackPlayer) {
+    if let Some(tick) = player.next_tick() {
+        queue.push(Reverse((tick, slot)));
+    }
+}
+
+// starts the tracks over from `tick`, sounding again what is held there
+async fn jump(
+    players: &mut [TrackPlayer],
+    queue: &mut EventQueue,
+    tick: u64,
+    timestamp: Instant,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    queue.clear();
+    for (slot, player) in players.iter_mut().enumerate() {
+        player.skip_to(tick);
+
+        for (key, vel) in player.notes.held.clone() {
+            player
+                .notes
+                .handle_note_update(timestamp, key, vel, None)
+                .await?;
+        }
+
+        enqueue(queue, slot, player);
+    }
+
+    Ok(())
+}
+
+/// Plays the tracks from a single task, taking their events from a queue in the order they
+/// happen, and returns how many notes each track sounded at once
+pub async fn play_sequence(
+    tracks: Vec<Track>,
+    mut context: PlayContext,
+) -> Result<Vec<InstrumentCount>, Box<dyn std::error::Error + Send + Sync>> {
+    let options = context.options.clone();
+    let mut seek = context.seek.take();
+    let mut stop = context.stop.clone();
+    let ticks_per_beat = context.timing.ticks_per_beat;
+    let mut tick_us = context.timing.tick.as_micros() as u32;
+
+    let (tick_update_tx, mut tick_update_rx) = mpsc::unbounded_channel();
+
+    let start = context.start;
+    // the time of the current position, from the start of playback
+    let mut offset = Duration::ZERO;
+
+    let tempo_driver = context
+        .tempo_schedule
+        .clone()
+        .map(|schedule| tokio::spawn(drive_tempo(schedule, start, tick_update_tx.clone())));
+
+    let mut players = tracks
+        .into_iter()
+        .map(|track| TrackPlayer::new(track, &context, start))
+        .collect::<Vec<_>>();
+
+    let mut queue = EventQueue::new();
+    for (slot, player) in players.iter_mut().enumerate() {
+        // what tracks played early hold at the start
+        for (key, vel) in player.notes.held.clone() {
+            player
+                .notes
+                .handle_note_update(start, key, vel, None)
+                .await?;
+        }
+
+        enqueue(&mut queue, slot, player);
+    }
+
+    let mut position = 0;
+    let mut repeats = 0;
+    // set while a `@tempo-lock` directive is in effect
+    let mut tempo_locked = false;
+    // with --error-polyphony playback stops at the overlap, but is still cleaned up first
+    let mut failure = None;
+    // the ramp of --smooth-tempo in progress, replaced by the next tempo update
+    let mut tempo_ramp: Option<JoinHandle<_>> = None;
+
+    loop {
+        let repeat = context
+            .repeat
+            .filter(|section| section.count.is_none_or(|count| repeats < count));
+
+        if let Some(section) = repeat {
+            if queue
+                .peek()
+                .is_none_or(|Reverse((tick, _))| *tick >= section.end_tick)
+            {
+                // play on until the end of the section, even if nothing is left in it
+                let remaining_ticks = section.end_tick.saturating_sub(position) as u32;
+                offset += Duration::from_micros(remaining_ticks as u64 * tick_us as u64);
+
+                let stopped = {
+                    let sleep = sleep_until(
+                        start,
+                        &mut offset,
+                        remaining_ticks,
+                        &mut tick_us,
+                        &mut tick_update_rx,
+                        &mut stop,
+                    );
+                    tokio::pin!(sleep);
+
+                    loop {
+                        let due = next_delayed(&players);
+
+                        tokio::select! {
+                            stopped = &mut sleep => break stopped,
+                            _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                                let due = due.unwrap_or_else(Instant::now);
+                                failure = play_delayed(&mut players, due, false).await?;
+                                if failure.is_some() {
+                                    break true;
+                                }
+                            }
+                        }
+                    }
+                };
+
+                for player in &mut players {
+                    player.notes.release_held_notes(start + offset).await?;
+                }
+
+                if stopped {
+                    break;
+                }
+
+                position = section.start_tick;
+                tick_us = section.start_tick_us;
+                // the section starts over at its own tempo
+                if let Some(ramp) = tempo_ramp.take() {
+                    ramp.abort();
+                }
+                repeats += 1;
+
+                for player in &mut players {
+                    player.notes.transpose = repeats as i32 * section.transpose as i32;
+                }
+                jump(&mut players, &mut queue, section.start_tick, start + offset).await?;
+
+                continue;
+            }
+        }
+
+        let Some(Reverse((tick, slot))) = queue.pop() else {
+            // the delayed tracks play on after the end of the others
+            while let Some(due) = next_delayed(&players) {
+                if *stop.borrow() {
+                    break;
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep_until(due) => {
+                        failure = play_delayed(&mut players, due, repeat.is_none()).await?;
+                        if failure.is_some() {
+                            break;
+                        }
+                    }
+                    Ok(()) = stop.changed() => continue,
+                }
+            }
+            if failure.is_some() {
+                break;
+            }
+
+            // with --scrub the end waits for the next position to play from
+            let target = loop {
+                if seek.is_none() || *stop.borrow() {
+                    break None;
+                }
+
+                tokio::select! {
+                    target = next_seek(&mut seek) => break target,
+                    Ok(()) = stop.changed() => continue,
+                }
+            };
+            let Some(target) = target else {
+                break;
+            };
+
+            // notes may still be held with --hold-end
+            let now = Instant::now();
+            for player in &mut players {
+                player.notes.release_held_notes(now).await?;
+            }
+
+            offset = now.saturating_duration_since(start);
+            position = target.tick;
+            tick_us = target.tick_us;
+            jump(&mut players, &mut queue, target.tick, now).await?;
+            continue;
+        };
+
+        let player = &mut players[slot];
+        let Some((track_tick, track_event)) = player.next.take() else {
+            continue;
+        };
+        player.advance();
+        enqueue(&mut queue, slot, player);
+
+        let delta = (tick - position) as u32;
+        position = tick;
+
+        offset += Duration::from_micros(delta as u64 * tick_us as u64);
+
+        // notes reaching --max-note-duration are released and the events of delayed tracks
+        // played while waiting for the next event
+        let wake = {
+            let sleep = sleep_until(
+                start,
+                &mut offset,
+                delta,
+                &mut tick_us,
+                &mut tick_update_rx,
+                &mut stop,
+            );
+            tokio::pin!(sleep);
+
+            loop {
+                let expiry = players
+                    .iter()
+                    .filter_map(|player| player.notes.next_expiry())
+                    .min();
+                let due = next_delayed(&players);
+
+                tokio::select! {
+                    stopped = &mut sleep => break if stopped { Wake::Stopped } else { Wake::Due },
+                    Some(target) = next_seek(&mut seek) => break Wake::Seek(target),
+                    _ = tokio::time::sleep_until(expiry.unwrap_or_else(Instant::now)), if expiry.is_some() => {
+                        let expiry = expiry.unwrap_or_else(Instant::now);
+                        for player in &mut players {
+                            for key in player.notes.release_expired(expiry).await? {
+                                warn!(
+                                    "track {}: key {key} reached the maximum note duration, releasing it",
+                                    player.index
+                                );
+                            }
+                        }
+                    }
+                    _ = tokio::time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                        let due = due.unwrap_or_else(Instant::now);
+                        if let Some(message) = play_delayed(&mut players, due, repeat.is_none()).await? {
+                            break Wake::Failed(message);
+                        }
+                    }
+                }
+            }
+        };
+
+        match wake {
+            Wake::Due => (),
+            Wake::Stopped => {
+                // release what the tracks are holding so that nothing is left sounding when the
+                // device is reset after playback, unless the reset fades it out
+                if options.soft_reset.is_none() {
+                    for player in &mut players {
+                        player.notes.release_held_notes(Instant::now()).await?;
+                    }
+                }
+                break;
+            }
+            Wake::Seek(target) => {
+                let now = Instant::now();
+                for player in &mut players {
+                    player.notes.release_held_notes(now).await?;
+                }
+                if let Some(ramp) = tempo_ramp.take() {
+                    ramp.abort();
+                }
+
+                offset = now.saturating_duration_since(start);
+                position = target.tick;
+                tick_us = target.tick_us;
+                jump(&mut players, &mut queue, target.tick, now).await?;
+                continue;
+            }
+            Wake::Failed(message) => {
+                failure = Some(message);
+                break;
+            }
+        }
+
+        // events left out with --enable-events are passed over as if they weren't there
+        let kind = track_event.kind.filter(|kind| options.is_enabled(kind));
+
+        // the tempo is the one of the sequence, so it isn't delayed along with the track
+        if matches!(
+            kind,
+            Some(
+                EventKind::TempoUpdate(_)
+                    | EventKind::Directive(Directive::TempoLock | Directive::TempoUnlock)
+            )
+        ) {
+            yield_event(&players[slot], kind.as_ref())?;
+        }
+        let kind = match kind {
+            Some(EventKind::TempoUpdate(new_us_per_beat)) => {
+                if !options.fixed_tempo && !options.external_tempo && !tempo_locked {
+                    if !TEMPO_RANGE.contains(&new_us_per_beat) {
+                        warn!(
+                            "track {}: tempo of {new_us_per_beat} µs per beat is out of range, clamping it",
+                            players[slot].index
+                        );
+                    }
+                    let new_us_per_beat = clamp_tempo(new_us_per_beat);
+
+                    if let Some(ramp) = tempo_ramp.take() {
+                        ramp.abort();
+                    }
+
+                    match options.smooth_tempo {
+                        Some(duration) => {
+                            tempo_ramp = Some(tokio::spawn(ramp_tempo(
+                                tick_us,
+                                new_us_per_beat,
+                                ticks_per_beat,
+                                options.speed.tempo,
+                                duration,
+                                tick_update_tx.clone(),
+                            )))
+                        }
+                        None => {
+                            handle_tempo_update(
+                                new_us_per_beat,
+                                ticks_per_beat,
+                                options.speed.tempo,
+                                &tick_update_tx,
+                            )
+                            .await?
+                        }
+                    }
+                }
+                None
+            }
+            Some(EventKind::Directive(Directive::TempoLock)) => {
+                tempo_locked = true;
+                None
+            }
+            Some(EventKind::Directive(Directive::TempoUnlock)) => {
+                tempo_locked = false;
+                None
+            }
+            kind => kind,
+        };
+
+        let player = &mut players[slot];
+        if player.delay.is_zero() {
+            failure = play_event(
+                &mut players,
+                slot,
+                track_tick,
+                kind,
+                start + offset,
+                repeat.is_none(),
+            )
+            .await?;
+            if failure.is_some() {
+                break;
+            }
+        } else {
+            let due = start + offset + player.delay;
+            player.delayed.push_back((due, track_tick, kind));
+        }
+    }
+    if let Some(tempo_driver) = tempo_driver {
+        tempo_driver.abort();
+    }
+    if let Some(ramp) = tempo_ramp {
+        ramp.abort();
+    }
+
+    if failure.is_some() {
+        for player in &mut players {
+            player.notes.release_held_notes(Instant::now()).await?;
+        }
+    }
+
+    let mut counts = Vec::new();
+    for player in players {
+        counts.push(player.notes.count);
+
+        // lets the arpeggiator or the glider silence its last note and finish
+        drop(player.notes);
+        if let Some(tone_task) = player.tone_task {
+            tone_task.await??;
+        }
+    }
+
+    if let Some(message) = failure {
+        return Err(message.into());
+    }
+
+    Ok(counts)
+}
+
+/// Plays the tracks the way `play_sequence` does without sounding them, yielding the events of
+/// the tracks that are heard along with the index of their track as they are played. Ends with
+/// the error playback failed with, if any
+pub fn event_stream(
+    tracks: Vec<Track>,
+    mut context: PlayContext,
+) -> impl Stream<Item = Result<(usize, EventKind), Box<dyn std::error::Error + Send + Sync>>> {
+    let (events_tx, events_rx) = mpsc::unbounded_channel();
+    context.events = Some(events_tx);
+    // playback fails at its next event once the stream is dropped
+    let playback = tokio::spawn(play_sequence(tracks, context));
+
+    futures::stream::unfold(
+        (events_rx, Some(playback)),
+        |(mut events_rx, playback)| async move {
+            if let Some(event) = events_rx.recv().await {
+                return Some((Ok(event), (events_rx, playback)));
+            }
+
+            let failure = match playback?.await {
+                Ok(Ok(_)) => return None,
+                Ok(Err(e)) => e,
+                Err(e) => e.into(),
+            };
+            Some((Err(failure), (events_rx, None)))
+        },
+    )
+}
+
+/// Plays the notes of the input as they arrive until it is closed or playback is stopped, and
+/// returns how many notes were sounding at once
+pub async fn play_live(
+    mut input: MidiInput,
+    device: DeviceHandle,
+    osc: Option<Arc<OscSink>>,
+    options: Arc<PlayOptions>,
+    mut stop: watch::Receiver<bool>,
+) -> Result<InstrumentCount, Box<dyn std::error::Error + Send + Sync>> {
+    let (held_tones, tone_task) = spawn_tone_task(&device, &options);
+
+    let mut notes = TrackNotes {
+        track_index: 0,
+        device,
+        osc,
+        instrument_count: Arc::new(Mutex::new(InstrumentCount::default())),
+        options: options.clone(),
+        start: Instant::now(),
+        held: HashMap::new(),
+        count: InstrumentCount::default(),
+        controllers: Controllers::default(),
+        held_tones,
+        channels: HashMap::new(),
+        transpose: 0,
+        dropped_keys: HashSet::new(),
+        started: HashMap::new(),
+        voices: HashMap::new(),
+        tuning: Tuning::new(),
+        frequencies: HashMap::new(),
+    };
+
+    while !*stop.borrow() {
+        let event = tokio::select! {
+            event = input.next() => event?,
+            Ok(()) = stop.changed() => continue,
+        };
+
+        let Some(event) = event else {
+            break;
+        };
+        if !options.is_enabled(&event) {
+            continue;
+        }
+
+        let now = Instant::now();
+        match event {
+            EventKind::NoteUpdate {
+                channel,
+                key,
+                vel,
+                release_vel,
+            } => {
+                notes.assign_voice(key, channel, vel);
+                notes.handle_note_update(now, key, vel, release_vel).await?;
+            }
+            EventKind::Aftertouch { key, value } if options.aftertouch => {
+                notes.handle_aftertouch(now, key, value).await?;
+            }
+            EventKind::Controller { controller, value } => {
+                notes.controllers.update(controller, value);
+            }
+            _ => (),
+        }
+    }
+
+    // the reset after playback fades them out with --soft-reset
+    if options.soft_reset.is_none() {
+        notes.release_held_notes(Instant::now()).await?;
+    }
+
+    let count = notes.count;
+
+    drop(notes);
+    if let Some(tone_task) = tone_task {
+        tone_task.await??;
+    }
+
+    Ok(count)
+}
+
+/// Sounds the tones of the script at their times until its end or playback is stopped, and
+/// silences what is still sounding then
+pub async fn play_freq_script(
+    script: FreqScript,
+    device: DeviceHandle,
+    mut stop: watch::Receiver<bool>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let start = Instant::now();
+    let mut sounding = Vec::new();
+
+    for (time, tone) in script.steps {
+        let stopped = loop {
+            if *stop.borrow() {
+                break true;
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep_until(start + time) => break false,
+                Ok(()) = stop.changed() => continue,
+            }
+        };
+
+        if stopped {
+            break;
+        }
+
+        sounding.retain(|frequency| *frequency != tone.frequency);
+        if tone.vel != 0 {
+            sounding.push(tone.frequency);
+        }
+
+        device.tone_update(start + time, tone).await?;
+    }
+
+    for frequency in sounding {
+        device
+            .tone_update(Instant::now(), Tone::off(frequency))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use async_trait::async_trait;
+    use futures::StreamExt;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+
+    use super::*;
+    use crate::{
+        args::Args,
+        device::{self, Device, DummyDevice, ZeroVelocity},
+        midi::tests::{note, parse, smf, tempo, TICKS_PER_BEAT},
+    };
+
+    // (ms since playback started, frequency, velocity) of every tone a device was sent
+    type Sent = Arc<std::sync::Mutex<Vec<(u64, u16, u8)>>>;
+
+    /// Keeps the tones it is sent, along with when they were sent
+    struct RecordingDevice {
+        start: Instant,
+        sent: Sent,
+    }
+
+    #[async_trait]
+    impl Device for RecordingDevice {
+        async fn tone_update(
+            &mut self,
+            frequency: u16,
+            vel: u8,
+        ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            let ms = self.start.elapsed().as_millis() as u64;
+            self.sent.lock().unwrap().push((ms, frequency, vel));
+            Ok(())
+        }
+
+        async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+            Ok(())
+        }
+
+        async fn verify_id(
+            &mut self,
+        ) -> Result<Result<(), [u8; 4]>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(Ok(()))
+        }
+    }
+
+    // plays the file through the writer onto a recording device with the options of the
+    // command line, the way main does without a tempo file or a repeated section. Run on a
+    // paused clock, the times are exact and nothing is waited for
+    async fn play_file(name: &str, file: &[u8], options: &[&str]) -> Vec<(u64, u16, u8)> {
+        let (played, sent) = try_play_file(name, file, options).await;
+        played.unwrap();
+        sent
+    }
+
+    // what main plays with, without a tempo file or a repeated section
+    fn context(
+        timing: Timing,
+        args: Args,
+        device: DeviceHandle,
+        start: Instant,
+        stop: watch::Receiver<bool>,
+    ) -> PlayContext {
+        PlayContext {
+            timing,
+            device,
+            instrument_count: Arc::new(Mutex::new(InstrumentCount::default())),
+            options: Arc::new(args.play_options),
+            repeat: None,
+            tempo_schedule: None,
+            osc: None,
+            start,
+            stop,
+            seek: None,
+            events: None,
+        }
+    }
+
+    // plays a file like play_file, also returning how playback ended
+    async fn try_play_file(
+        name: &str,
+        file: &[u8],
+        options: &[&str],
+    ) -> (
+        Result<Vec<InstrumentCount>, Box<dyn std::error::Error + Send + Sync>>,
+        Vec<(u64, u16, u8)>,
+    ) {
+        let command_line = [&["play-midi", "-d"], options].concat();
+        let args = Args::parse_from(&command_line);
+        let sequence = parse(name, file).await.unwrap();
+
+        let start = Instant::now();
+        let sent = Sent::default();
+        let device = Box::new(RecordingDevice {
+            start,
+            sent: sent.clone(),
+        });
+        let (device, writer) = device::spawn_writer(device, ZeroVelocity::NoteOff, None);
+
+        let (_stop_tx, stop_rx) = watch::channel(false);
+        let context = context(sequence.timing, args, device.clone(), start, stop_rx);
+        let played = play_sequence(sequence.tracks, context).await;
+
+        drop(device);
+        writer.await.unwrap().unwrap();
+
+        let sent = sent.lock().unwrap().clone();
+        (played, sent)
+    }
+
+    // a beat in the ticks of the test files
+    const BEAT: u32 = TICKS_PER_BEAT as u32;
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_scale() {
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, note(60, 100)),
+            (BEAT, note(60, 0)),
+            (0, note(62, 90)),
+            (BEAT, note(62, 0)),
+            (0, note(64, 80)),
+            (BEAT, note(64, 0)),
+        ];
+
+        let sent = play_file("scale", &smf(&[track]), &[]).await;
+
+        assert_eq!(
+            sent,
+            [
+                (0, 523, 100),
+                (500, 523, 0),
+                (500, 587, 90),
+                (1000, 587, 0),
+                (1000, 659, 80),
+                (1500, 659, 0),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_chord() {
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, note(60, 100)),
+            (0, note(64, 100)),
+            (0, note(67, 100)),
+            (BEAT, note(60, 0)),
+            (0, note(64, 0)),
+            (0, note(67, 0)),
+        ];
+
+        let sent = play_file("chord", &smf(&[track]), &[]).await;
+
+        assert_eq!(
+            sent,
+            [
+                (0, 523, 100),
+                (0, 659, 100),
+                (0, 784, 100),
+                (500, 523, 0),
+                (500, 659, 0),
+                (500, 784, 0),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_tempo_change() {
+        let conductor = vec![(0, tempo(500_000)), (BEAT, tempo(250_000))];
+        let notes = vec![
+            (0, note(72, 100)),
+            (BEAT, note(72, 0)),
+            (0, note(72, 100)),
+            (BEAT, note(72, 0)),
+        ];
+
+        let sent = play_file("tempo-change", &smf(&[conductor, notes]), &[]).await;
+
+        // the second beat is twice as fast
+        assert_eq!(
+            sent,
+            [
+                (0, 1046, 100),
+                (500, 1046, 0),
+                (500, 1046, 100),
+                (750, 1046, 0)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_dense_tempo_changes() {
+        // the tempo changes on every tick, alternating between ticks of 5 and 2.5 ms
+        let conductor = (0..2 * BEAT)
+            .map(|tick| (tick.min(1), tempo([480_000, 240_000][tick as usize % 2])))
+            .collect();
+        let melody = vec![
+            (0, note(60, 100)),
+            (BEAT, note(60, 0)),
+            (0, note(60, 100)),
+            (BEAT, note(60, 0)),
+        ];
+        let offbeat = vec![(BEAT / 2, note(64, 100)), (BEAT, note(64, 0))];
+
+        let file = smf(&[conductor, melody, offbeat]);
+
+        // a ramp as short as its step lands on the tempo at once, so every ramp but the last is
+        // cut short by the next change without changing the times
+        for options in [&[][..], &["--smooth-tempo=10"]] {
+            let sent = play_file("dense-tempo", &file, options).await;
+
+            // every track follows all the changes, a beat is 48 of each
+            assert_eq!(
+                sent,
+                [
+                    (0, 523, 100),
+                    (180, 659, 100),
+                    (360, 523, 0),
+                    (360, 523, 100),
+                    (540, 659, 0),
+                    (720, 523, 0),
+                ],
+                "{options:?}"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_notes_under_pedal_held_to_end() {
+        // the sustain pedal goes down and is still down when the track ends, the last note
+        // isn't even let go
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, vec![0xb0, 64, 127]),
+            (0, note(60, 100)),
+            (BEAT, note(60, 0)),
+            (0, note(64, 100)),
+            (BEAT, vec![0xb0, 64, 127]),
+        ];
+
+        let sent = play_file("pedal", &smf(&[track]), &[]).await;
+
+        // nothing is left sounding once the track ends
+        assert_eq!(
+            sent,
+            [
+                (0, 523, 100),
+                (500, 523, 0),
+                (500, 659, 100),
+                (1000, 659, 0),
+            ]
+        );
+    }
+
+    thread_local! {
+        // the levels of the records logged on the thread of a test
+        static LOGGED: RefCell<Vec<Level>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct RecordingLogger;
+
+    impl Log for RecordingLogger {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            LOGGED.with(|logged| logged.borrow_mut().push(record.level()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: RecordingLogger = RecordingLogger;
+
+    // the levels logged by the thread from now on
+    fn record_logs() {
+        // only the first test to get here sets it
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(LevelFilter::Trace);
+        LOGGED.with(|logged| logged.borrow_mut().clear());
+    }
+
+    fn logged(level: Level) -> usize {
+        LOGGED.with(|logged| logged.borrow().iter().filter(|l| **l == level).count())
+    }
+
+    #[tokio::test]
+    async fn tempo_update_after_playback() {
+        record_logs();
+        let (tick_update_tx, tick_update_rx) = mpsc::unbounded_channel();
+        drop(tick_update_rx);
+
+        assert!(!send_tick(&tick_update_tx, 1000));
+        handle_tempo_update(500_000, 96, 1.0, &tick_update_tx)
+            .await
+            .unwrap();
+
+        assert_eq!(logged(Level::Error) + logged(Level::Warn), 0);
+        // the dropped updates are only noted
+        assert_eq!(logged(Level::Debug), 2);
+    }
+
+    #[tokio::test]
+    async fn tempo_sources_outlive_playback() {
+        record_logs();
+        let (tick_update_tx, tick_update_rx) = mpsc::unbounded_channel();
+
+        let ramp = tokio::spawn(ramp_tempo(
+            5000,
+            250_000,
+            96,
+            1.0,
+            Duration::from_millis(30),
+            tick_update_tx.clone(),
+        ));
+        let schedule = vec![
+            (Duration::ZERO, 5000),
+            (Duration::from_millis(10), 4000),
+            (Duration::from_millis(20), 3000),
+        ];
+        let driver = tokio::spawn(drive_tempo(schedule, Instant::now(), tick_update_tx));
+
+        // playback ends while the tempo is still changing
+        drop(tick_update_rx);
+
+        ramp.await.unwrap().unwrap();
+        driver.await.unwrap().unwrap();
+        assert_eq!(logged(Level::Error) + logged(Level::Warn), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_notes_left_sounding() {
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, note(60, 100)),
+            (BEAT, note(60, 0)),
+            (0, note(64, 100)),
+            // the track ends a beat later without a note off
+            (BEAT, vec![0xff, 0x01, 0x00]),
+        ];
+
+        let sent = play_file("missing-note-off", &smf(&[track]), &[]).await;
+
+        assert_eq!(
+            sent,
+            [
+                (0, 523, 100),
+                (500, 523, 0),
+                (500, 659, 100),
+                (1000, 659, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_low_semitones_apart_in_centihertz() {
+        // an octave down, where whole hertz no longer tell the lowest keys apart
+        let options = Args::parse_from(&["play-midi", "-d", "--octave=-1"]).play_options;
+        let low = played_centihertz(0, 0, 0, &Tuning::new(), &options).unwrap();
+        let next = played_centihertz(1, 0, 0, &Tuning::new(), &options).unwrap();
+
+        assert_eq!(centihertz_to_hertz(low), centihertz_to_hertz(next));
+        assert_ne!(low, next);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn offsets_track_in_time_across_tempo_changes() {
+        let tempo_track = vec![(0, tempo(500_000)), (BEAT, tempo(250_000))];
+        let track = vec![
+            (0, note(60, 100)),
+            (2 * BEAT, note(60, 0)),
+            (0, note(64, 100)),
+            (BEAT, note(64, 0)),
+        ];
+        let file = smf(&[tempo_track, track]);
+
+        let later = play_file("track-offset", &file, &["--track-offset", "1:100"]).await;
+        assert_eq!(
+            later,
+            [
+                (100, 523, 100),
+                (850, 523, 0),
+                (850, 659, 100),
+                (1100, 659, 0)
+            ]
+        );
+
+        // the track played earlier starts with playback, the notes of the others would come
+        // 100 ms later
+        let earlier = play_file("track-offset", &file, &["--track-offset", "1:-100"]).await;
+        assert_eq!(
+            earlier,
+            [
+                (0, 523, 100),
+                (750, 523, 0),
+                (750, 659, 100),
+                (1000, 659, 0)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn streams_events_as_they_are_played() {
+        let tempo_track = vec![(0, tempo(500_000))];
+        let track = vec![(0, note(60, 100)), (BEAT, note(60, 0))];
+        let muted = vec![(0, note(72, 100)), (BEAT, note(72, 0))];
+        let sequence = parse("stream", &smf(&[tempo_track, track, muted]))
+            .await
+            .unwrap();
+
+        let args = Args::parse_from(&["play-midi", "-d", "--track-offset", "1:100", "--mute", "2"]);
+        let (device, _) = device::spawn_writer(
+            Box::new(DummyDevice::default()),
+            ZeroVelocity::NoteOff,
+            None,
+        );
+        let (_stop_tx, stop_rx) = watch::channel(false);
+        let start = Instant::now();
+        let context = context(sequence.timing, args, device, start, stop_rx);
+
+        let events = event_stream(sequence.tracks, context)
+            .map(|event| {
+                let (index, kind) = event.unwrap();
+                let note = match kind {
+                    EventKind::NoteUpdate { key, vel, .. } => Some((key, vel)),
+                    _ => None,
+                };
+                (start.elapsed().as_millis() as u64, index, note)
+            })
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            [
+                (0, 0, None),
+                (100, 1, Some((60, 100))),
+                (600, 1, Some((60, 0)))
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn plays_drums_by_channel_of_each_note() {
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, note(60, 100)),
+            (BEAT, note(60, 0)),
+            // a bass drum on channel 10 in the same track
+            (0, vec![0x99, 36, 100]),
+            (BEAT, vec![0x99, 36, 0]),
+        ];
+
+        let sent = play_file("drums", &smf(&[track]), &["--drum-mode"]).await;
+
+        assert_eq!(
+            sent,
+            [(0, 523, 100), (500, 523, 0), (500, 60, 100), (1000, 60, 0)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn releases_notes_on_polyphony_error() {
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, note(60, 100)),
+            (BEAT, note(64, 100)),
+            (BEAT, note(64, 0)),
+            (0, note(60, 0)),
+        ];
+
+        let (played, sent) =
+            try_play_file("polyphony-error", &smf(&[track]), &["--error-polyphony"]).await;
+
+        assert!(played.is_err());
+        assert_eq!(sent, [(0, 523, 100), (500, 523, 0)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn holds_notes_left_sounding_with_hold_end() {
+        let track = vec![
+            (0, tempo(500_000)),
+            (0, note(64, 100)),
+            (BEAT, vec![0xff, 0x01, 0x00]),
+        ];
+
+        let sent = play_file("hold-end", &smf(&[track]), &["--hold-end"]).await;
+
+        assert_eq!(sent, [(0, 659, 100)]);
+    }
 }
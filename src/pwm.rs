@@ -0,0 +1,166 @@
+use std::{path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use log::info;
+
+use crate::device::{centihertz_to_hertz, Device};
+
+/// Drives a speaker straight from a hardware PWM channel through the sysfs interface of Linux,
+/// e.g. on a Raspberry Pi. A channel can only sound one note at a time, a new note replaces the
+/// sounding one.
+pub struct PwmDevice {
+    // the pwmN directory of the channel
+    channel_path: PathBuf,
+    // the frequency that is sounding in hundredths of a hertz, 0 if none is
+    centihertz: u32,
+}
+
+impl PwmDevice {
+    pub async fn new(
+        chip: u32,
+        channel: u32,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let chip_path = PathBuf::from(format!("/sys/class/pwm/pwmchip{chip}"));
+        let channel_path = chip_path.join(format!("pwm{channel}"));
+
+        if !channel_path.exists() {
+            info!("exporting pwm channel {channel} of chip {chip}");
+            tokio::fs::write(chip_path.join("export"), channel.to_string())
+                .await
+                .map_err(|e| {
+                    format!("failed to export pwm channel {channel} of chip {chip}: {e}")
+                })?;
+
+            // the channel directory shows up asynchronously after the export
+            for _ in 0..50 {
+                if channel_path.exists() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        info!("using pwm device at {}", channel_path.to_string_lossy());
+
+        let mut dev = Self {
+            channel_path,
+            centihertz: 0,
+        };
+        dev.reset().await?;
+
+        Ok(dev)
+    }
+
+    async fn write_attribute(
+        &self,
+        attribute: &str,
+        value: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        tokio::fs::write(self.channel_path.join(attribute), value.to_string())
+            .await
+            .map_err(|e| format!("failed to write pwm {attribute}: {e}").into())
+    }
+
+    async fn set_period(
+        &self,
+        period_ns: u64,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // the duty cycle may never be longer than the period, so it is cleared before the period
+        // changes
+        self.write_attribute("duty_cycle", 0).await?;
+        self.write_attribute("period", period_ns).await?;
+        self.write_attribute("duty_cycle", period_ns * duty.min(100) as u64 / 100)
+            .await?;
+        self.write_attribute("enable", 1).await
+    }
+}
+
+#[async_trait]
+impl Device for PwmDevice {
+    async fn tone_update(
+        &mut self,
+        frequency: u16,
+        vel: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.tone_update_with_duty(frequency, vel, 50).await
+    }
+
+    async fn tone_update_with_duty(
+        &mut self,
+        frequency: u16,
+        vel: u8,
+        duty: u8,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if vel == 0 {
+            return self.note_off(frequency).await;
+        }
+        if frequency == 0 {
+            return Ok(());
+        }
+
+        self.set_period(1_000_000_000 / frequency as u64, duty)
+            .await?;
+        self.centihertz = frequency as u32 * 100;
+
+        Ok(())
+    }
+
+    async fn tone_update_centihertz(
+        &mut self,
+        centihertz: u32,
+        vel: u8,
+        duty: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if vel == 0 {
+            return self.note_off_centihertz(centihertz, None).await;
+        }
+        if centihertz == 0 {
+            return Ok(());
+        }
+
+        // the period is set in nanoseconds, which is finer than a hundredth of a hertz
+        self.set_period(100_000_000_000 / centihertz as u64, duty.unwrap_or(50))
+            .await?;
+        self.centihertz = centihertz;
+
+        Ok(())
+    }
+
+    async fn note_off(
+        &mut self,
+        frequency: u16,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // the note may already have been replaced by a newer one
+        if frequency == centihertz_to_hertz(self.centihertz) {
+            self.reset().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn note_off_centihertz(
+        &mut self,
+        centihertz: u32,
+        _release_vel: Option<u8>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if centihertz == self.centihertz {
+            self.reset().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.write_attribute("enable", 0).await?;
+        self.centihertz = 0;
+
+        Ok(())
+    }
+
+    async fn verify_id(
+        &mut self,
+    ) -> Result<Result<(), [u8; 4]>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Ok(()))
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+use log::{debug, warn};
+
+// the directory the state files are kept in, under the config directory of the user
+fn state_dir() -> Option<PathBuf> {
+    #[cfg(target_family = "windows")]
+    let config_dir = std::env::var_os("APPDATA").map(PathBuf::from);
+    #[cfg(not(target_family = "windows"))]
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    config_dir.map(|dir| dir.join("speaker_project"))
+}
+
+/// Reads a state file remembered from an earlier run, if there is one
+pub fn load(name: &str) -> Option<String> {
+    let path = state_dir()?.join(name);
+
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(contents.trim().to_string()),
+        Err(e) => {
+            debug!("no state at {}: {e}", path.to_string_lossy());
+            None
+        }
+    }
+}
+
+/// Remembers a state file for later runs, failing to do so only warns
+pub fn save(name: &str, contents: &str) {
+    let Some(dir) = state_dir() else {
+        warn!("no config directory to remember {name} in");
+        return;
+    };
+
+    if let Err(e) =
+        std::fs::create_dir_all(&dir).and_then(|_| std::fs::write(dir.join(name), contents))
+    {
+        warn!("failed to remember {name}: {e}");
+    }
+}
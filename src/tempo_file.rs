@@ -0,0 +1,106 @@
+use std::{path::Path, time::Duration};
+
+// how often the tempo is updated between two points of a linearly interpolated tempo file
+const LINEAR_STEP: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// keep the tempo of a point until the next one
+    Stepped,
+    /// glide from the tempo of a point to that of the next one
+    Linear,
+}
+
+/// A tempo map given as a text file of `time_seconds bpm` lines, replacing the tempo updates of
+/// the MIDI file. Empty lines and lines starting with `#` are skipped.
+#[derive(Debug, Clone)]
+pub struct TempoFile {
+    // (time from the start of playback, bpm), sorted by time
+    points: Vec<(Duration, f64)>,
+}
+
+impl TempoFile {
+    pub async fn load(
+        path: impl AsRef<Path>,
+    ) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let text = tokio::fs::read_to_string(&path).await?;
+
+        Self::parse(&text).map_err(|e| format!("{}: {e}", path.as_ref().to_string_lossy()).into())
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut points: Vec<(Duration, f64)> = Vec::new();
+
+        for (number, line) in text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()))
+        {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let (Some(time), Some(bpm), None) = (words.next(), words.next(), words.next()) else {
+                return Err(format!("line {number}: expected `time_seconds bpm`"));
+            };
+
+            let time = time
+                .parse::<f64>()
+                .ok()
+                .and_then(|time| Duration::try_from_secs_f64(time).ok())
+                .ok_or_else(|| format!("line {number}: invalid time `{time}`"))?;
+            let bpm = bpm
+                .parse::<f64>()
+                .ok()
+                .filter(|bpm| bpm.is_finite() && *bpm > 0.0)
+                .ok_or_else(|| format!("line {number}: invalid bpm `{bpm}`"))?;
+
+            if points.last().is_some_and(|(last, _)| *last >= time) {
+                return Err(format!("line {number}: times must be increasing"));
+            }
+
+            points.push((time, bpm));
+        }
+
+        if points.is_empty() {
+            return Err("no tempo points".into());
+        }
+
+        Ok(Self { points })
+    }
+
+    /// The tick lengths in µs to switch to and when, starting with the one at the start of
+    /// playback. The tempo of the first point is used from the start.
+    pub fn schedule(
+        &self,
+        interpolation: Interpolation,
+        ticks_per_beat: u32,
+        tempo: f64,
+    ) -> Vec<(Duration, u32)> {
+        let tick_us =
+            |bpm: f64| (60_000_000.0 / bpm / ticks_per_beat as f64 / tempo).round() as u32;
+
+        let mut schedule = vec![(Duration::ZERO, tick_us(self.points[0].1))];
+
+        for (i, (time, bpm)) in self.points.iter().enumerate() {
+            schedule.push((*time, tick_us(*bpm)));
+
+            if let (Interpolation::Linear, Some((next_time, next_bpm))) =
+                (interpolation, self.points.get(i + 1))
+            {
+                let length = (*next_time - *time).as_secs_f64();
+                let mut step_time = *time + LINEAR_STEP;
+
+                while step_time < *next_time {
+                    let progress = (step_time - *time).as_secs_f64() / length;
+                    schedule.push((step_time, tick_us(bpm + (next_bpm - bpm) * progress)));
+                    step_time += LINEAR_STEP;
+                }
+            }
+        }
+
+        schedule.dedup_by_key(|(time, _)| *time);
+        schedule
+    }
+}
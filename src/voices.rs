@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+
+use log::info;
+
+use crate::midi::{EventKind, TempoMap, Track};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullPolicy {
+    /// drop the notes with the lowest velocity first
+    LowestVelocity,
+    /// drop the lowest notes first
+    LowestPitch,
+    /// drop the highest notes first
+    HighestPitch,
+}
+
+/// A note that is left out to stay within the voice limit, as (track index, tick, key)
+pub type CulledNote = (usize, u64, u8);
+
+/// Finds the notes to leave out so that no more than `max_voices` notes sound at once across
+/// the tracks of all the given sequences, each timed by its tempo map. Notes that already sound
+/// are kept, when the notes starting at a time don't all fit, the policy picks which of them are
+/// dropped. Logs what is dropped and where.
+pub fn cull(
+    sequences: &[(&[Track], &TempoMap)],
+    max_voices: usize,
+    policy: CullPolicy,
+) -> HashSet<CulledNote> {
+    // (time, tick, track index, key, velocity) of every note update, in the order they happen
+    let mut updates = sequences
+        .iter()
+        .flat_map(|&(tracks, tempo_map)| {
+            tracks.iter().flat_map(move |track| {
+                let index = track.index;
+                track
+                    .clone()
+                    .with_ticks()
+                    .filter_map(move |(tick, event)| match event.kind {
+                        Some(EventKind::NoteUpdate { key, vel, .. }) => {
+                            Some((tempo_map.time_at(tick), tick, index, key, vel))
+                        }
+                        _ => None,
+                    })
+            })
+        })
+        .collect::<Vec<_>>();
+    updates.sort_by_key(|(time, _, index, _, _)| (*time, *index));
+
+    let mut sounding = HashSet::new();
+    let mut culled = HashSet::new();
+
+    for time_updates in updates.chunk_by(|a, b| a.0 == b.0) {
+        let time = time_updates[0].0;
+
+        // note offs first, as they free voices for the notes starting at the same time
+        for (_, _, index, key, _) in time_updates.iter().filter(|update| update.4 == 0) {
+            sounding.remove(&(*index, *key));
+        }
+
+        let mut starting = time_updates
+            .iter()
+            .filter(|(_, _, index, key, vel)| *vel != 0 && !sounding.contains(&(*index, *key)))
+            .map(|(_, tick, index, key, vel)| (*tick, *index, *key, *vel))
+            .collect::<Vec<_>>();
+
+        // most important first, ties broken by track and key so that the result is deterministic
+        starting.sort_by_key(|(_, index, key, vel)| {
+            let rank = match policy {
+                CullPolicy::LowestVelocity => u8::MAX - vel,
+                CullPolicy::LowestPitch => u8::MAX - key,
+                CullPolicy::HighestPitch => *key,
+            };
+            (rank, *index, *key)
+        });
+
+        let free = max_voices.saturating_sub(sounding.len());
+        for (i, (tick, index, key, _)) in starting.into_iter().enumerate() {
+            if i < free {
+                sounding.insert((index, key));
+            } else {
+                info!(
+                    "{:.3} s: dropping key {key} of track {index}, {max_voices} voices are in use",
+                    time.as_secs_f64()
+                );
+                culled.insert((index, tick, key));
+            }
+        }
+    }
+
+    let per_track = culled.iter().fold(
+        HashMap::<usize, usize>::new(),
+        |mut counts, (index, _, _)| {
+            *counts.entry(*index).or_default() += 1;
+            counts
+        },
+    );
+    if !culled.is_empty() {
+        let mut per_track = per_track.into_iter().collect::<Vec<_>>();
+        per_track.sort();
+        info!(
+            "dropped {} note(s) to stay within {max_voices} voices, per track: {per_track:?}",
+            culled.len()
+        );
+    }
+
+    culled
+}
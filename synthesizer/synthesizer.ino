@@ -33,6 +33,64 @@ layout:
 send back: 61 d8 6e 1c
 
 these bytes are stored in "id"
+****************
+duty update message: like the update message, with
+the duty cycle of the tone in percent of the period
+layout:
+
+04 FF FF VV DD 04
+
+	* D is an 8 bit unsigned integer containing the
+percentage of the period the output is high, 1 to 50
+****************
+voice update message: like the duty update message, on
+the speaker with the given index instead of a free one
+layout:
+
+05 NN FF FF VV DD 05
+
+	* N is an 8 bit unsigned integer containing the index
+of the speaker, messages for speakers that don't exist
+are ignored
+****************
+note off message: turn off the speaker with a certain
+frequency. Firmware that tells the two apart keeps the
+speaker of an update message with a velocity of 0
+configured but muted, this one turns it off for both
+layout:
+
+06 FF FF 06
+****************
+release message: like the note off message, with the
+velocity the note was released with
+layout:
+
+07 FF FF RR 07
+
+	* R is an 8 bit unsigned integer containing the release
+velocity, which the speakers have no use for
+****************
+centihertz message: like the duty update message, with
+the frequency in hundredths of a hertz, which keeps the
+low notes in tune
+layout:
+
+08 CC CC CC CC VV DD 08
+
+	* C is a 32 bit unsigned integer representing the
+frequency in hundredths of a hertz. With a velocity of
+0 it stops the speaker sounding exactly that frequency,
+which tells apart low notes of the same whole hertz
+****************
+get features message: return a byte with a bit set for
+every optional message this firmware takes
+layout:
+
+09
+
+send back: FF
+
+	* bit 0 (0x01): the centihertz message
  */
 
 enum class MessageType
@@ -40,13 +98,25 @@ enum class MessageType
 	NoteUpdate = 0x01,
 	Reset = 0x02,
 	GetId = 0x03,
+	DutyUpdate = 0x04,
+	VoiceUpdate = 0x05,
+	NoteOff = 0x06,
+	Release = 0x07,
+	CentihertzUpdate = 0x08,
+	GetFeatures = 0x09,
 };
 
 enum class MessageLength
 {
 	NoteUpdate = 5,
 	Reset = 1,
-	GetId = 1
+	GetId = 1,
+	DutyUpdate = 6,
+	VoiceUpdate = 7,
+	NoteOff = 4,
+	Release = 5,
+	CentihertzUpdate = 8,
+	GetFeatures = 1
 };
 
 #define ID_LEN 4
@@ -56,6 +126,9 @@ const byte id[4] = {
 	0x6e,
 	0x1c};
 
+#define FEATURE_CENTIHERTZ 0x01
+const byte features = FEATURE_CENTIHERTZ;
+
 /* SPEAKER
 ****************
 A speaker represents a piezo or other sound-generating
@@ -65,7 +138,8 @@ timers to be used and the channel, however this must be
 done manually by looking up the values in the datasheet
 of the STM32F411CEU6
 
-frequency == 0 indicates that the speaker is not in use
+frequency == 0 indicates that the speaker is not in use,
+centihertz holds the same frequency in hundredths of a hertz
 */
 struct Speaker
 {
@@ -73,16 +147,36 @@ struct Speaker
 	int channel;
 	PinName pin_name;
 	int frequency = 0;
+	uint32_t centihertz = 0;
 
-	void play_frequency(int freq)
+	void play_frequency(int freq, int duty = 50)
 	{
 		this->frequency = freq;
-		this->timer->setPWM(this->channel, this->pin_name, this->frequency, 50);
+		this->centihertz = (uint32_t)freq * 100;
+		this->timer->setPWM(this->channel, this->pin_name, this->frequency, duty);
+	}
+
+	void play_centihertz(uint32_t centihertz, int duty = 50)
+	{
+		this->frequency = centihertz / 100;
+		this->centihertz = centihertz;
+
+		// the period in ticks of the timer clock, divided down to
+		// fit the 16 bit counters
+		uint64_t ticks = (uint64_t)this->timer->getTimerClkFreq() * 100 / centihertz;
+		uint32_t prescaler = ticks / 0x10000 + 1;
+
+		this->timer->setMode(this->channel, TIMER_OUTPUT_COMPARE_PWM1, this->pin_name);
+		this->timer->setPrescaleFactor(prescaler);
+		this->timer->setOverflow(ticks / prescaler, TICK_FORMAT);
+		this->timer->setCaptureCompare(this->channel, duty, PERCENT_COMPARE_FORMAT);
+		this->timer->resume();
 	}
 
 	void turn_off()
 	{
 		this->frequency = 0;
+		this->centihertz = 0;
 		this->timer->pause();
 	}
 };
@@ -184,7 +278,7 @@ void read_from_serial()
 	}
 }
 
-void update_note(uint16_t frequency, uint8_t velocity)
+void update_note(uint16_t frequency, uint8_t velocity, uint8_t duty = 50)
 {
 	if (velocity == 0)
 	{
@@ -205,7 +299,7 @@ void update_note(uint16_t frequency, uint8_t velocity)
 		{
 			if (speakers[i].frequency == 0) // find a free speaker
 			{
-				speakers[i].play_frequency(frequency);
+				speakers[i].play_frequency(frequency, duty);
 				break;
 			}
 		}
@@ -217,6 +311,50 @@ void update_note(uint16_t frequency, uint8_t velocity)
 	}
 }
 
+void update_centihertz(uint32_t centihertz, uint8_t velocity, uint8_t duty)
+{
+	if (velocity == 0 || centihertz == 0)
+	{
+		// turn off the speaker sounding exactly this frequency
+		for (int i = 0; i < NUM_SPEAKERS; i++)
+		{
+			if (speakers[i].frequency != 0 && speakers[i].centihertz == centihertz)
+			{
+				speakers[i].turn_off();
+				break;
+			}
+		}
+		return;
+	}
+
+	for (int i = 0; i < NUM_SPEAKERS; i++)
+	{
+		if (speakers[i].frequency == 0) // find a free speaker
+		{
+			speakers[i].play_centihertz(centihertz, duty);
+			break;
+		}
+	}
+}
+
+void update_voice(uint8_t voice, uint16_t frequency, uint8_t velocity, uint8_t duty)
+{
+	if (voice >= NUM_SPEAKERS)
+	{
+		return;
+	}
+
+	if (velocity != 0)
+	{
+		speakers[voice].play_frequency(frequency, duty);
+	}
+	else if (speakers[voice].frequency == frequency)
+	{
+		// only stop the speaker if it is still playing this note
+		speakers[voice].turn_off();
+	}
+}
+
 void pop_message(uint8_t message_length)
 {
 	/* move everything in the serial buffer to the left by message_length
@@ -294,6 +432,8 @@ void loop()
 		}
 
 		pop_message(static_cast<uint8_t>(MessageLength::Reset));
+
+		break;
 	}
 	case static_cast<uint8_t>(MessageType::GetId):
 	{
@@ -309,8 +449,128 @@ void loop()
 		Serial.flush();
 
 		pop_message(static_cast<uint8_t>(MessageLength::GetId));
+
+		break;
+	}
+	case static_cast<uint8_t>(MessageType::DutyUpdate):
+	{
+		// 0x04 FF FF VV DD 0x04
+
+		if (cursor_pos < static_cast<uint8_t>(MessageLength::DutyUpdate))
+		{
+			wait_for_message();
+			break;
+		}
+
+		uint16_t frequency = ((uint16_t)serial_buf[1] << 8) | ((uint16_t)serial_buf[2]);
+		uint8_t velocity = serial_buf[3];
+		uint8_t duty = serial_buf[4];
+
+		update_note(frequency, velocity, duty);
+
+		pop_message(static_cast<uint8_t>(MessageLength::DutyUpdate));
+
+		break;
+	}
+	case static_cast<uint8_t>(MessageType::VoiceUpdate):
+	{
+		// 0x05 NN FF FF VV DD 0x05
+
+		if (cursor_pos < static_cast<uint8_t>(MessageLength::VoiceUpdate))
+		{
+			wait_for_message();
+			break;
+		}
+
+		uint8_t voice = serial_buf[1];
+		uint16_t frequency = ((uint16_t)serial_buf[2] << 8) | ((uint16_t)serial_buf[3]);
+		uint8_t velocity = serial_buf[4];
+		uint8_t duty = serial_buf[5];
+
+		update_voice(voice, frequency, velocity, duty);
+
+		pop_message(static_cast<uint8_t>(MessageLength::VoiceUpdate));
+
+		break;
+	}
+	case static_cast<uint8_t>(MessageType::NoteOff):
+	{
+		// 0x06 FF FF 0x06
+
+		if (cursor_pos < static_cast<uint8_t>(MessageLength::NoteOff))
+		{
+			wait_for_message();
+			break;
+		}
+
+		uint16_t frequency = ((uint16_t)serial_buf[1] << 8) | ((uint16_t)serial_buf[2]);
+
+		update_note(frequency, 0);
+
+		pop_message(static_cast<uint8_t>(MessageLength::NoteOff));
+
+		break;
+	}
+	case static_cast<uint8_t>(MessageType::Release):
+	{
+		// 0x07 FF FF RR 0x07
+
+		if (cursor_pos < static_cast<uint8_t>(MessageLength::Release))
+		{
+			wait_for_message();
+			break;
+		}
+
+		uint16_t frequency = ((uint16_t)serial_buf[1] << 8) | ((uint16_t)serial_buf[2]);
+
+		update_note(frequency, 0);
+
+		pop_message(static_cast<uint8_t>(MessageLength::Release));
+
+		break;
+	}
+	case static_cast<uint8_t>(MessageType::CentihertzUpdate):
+	{
+		// 0x08 CC CC CC CC VV DD 0x08
+
+		if (cursor_pos < static_cast<uint8_t>(MessageLength::CentihertzUpdate))
+		{
+			wait_for_message();
+			break;
+		}
+
+		uint32_t centihertz = ((uint32_t)serial_buf[1] << 24) | ((uint32_t)serial_buf[2] << 16) |
+							  ((uint32_t)serial_buf[3] << 8) | ((uint32_t)serial_buf[4]);
+		uint8_t velocity = serial_buf[5];
+		uint8_t duty = serial_buf[6];
+
+		update_centihertz(centihertz, velocity, duty);
+
+		pop_message(static_cast<uint8_t>(MessageLength::CentihertzUpdate));
+
+		break;
+	}
+	case static_cast<uint8_t>(MessageType::GetFeatures):
+	{
+		// 0x09
+
+		if (cursor_pos < static_cast<uint8_t>(MessageLength::GetFeatures))
+		{
+			wait_for_message();
+			break;
+		}
+
+		Serial.write(features);
+		Serial.flush();
+
+		pop_message(static_cast<uint8_t>(MessageLength::GetFeatures));
+
+		break;
 	}
 	default:
+		// a message this firmware doesn't know, its type byte is
+		// dropped so that it can't block the ones after it
+		pop_message(1);
 		break;
 	}
 }